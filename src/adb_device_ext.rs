@@ -1,11 +1,20 @@
-use std::{net::SocketAddrV4, str::FromStr, vec::Vec};
+use std::{net::SocketAddrV4, str::FromStr, sync::LazyLock, vec::Vec};
 
 use adb_client::{ADBDeviceExt, ADBServer, ADBServerDevice};
 
 use std::error::Error;
 
+use regex::Regex;
+
 use crate::def::Config;
 
+static BATTERY_LEVEL: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"level: (\d+)").unwrap());
+static BATTERY_CHARGING: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(AC|USB|Wireless|Dock) powered: true").unwrap());
+static LOCKSCREEN_SHOWING: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(mShowingLockscreen|isKeyguardShowing|mDreamingLockscreen)=true").unwrap()
+});
+
 pub(crate) trait ADBDeviceRunCommand {
     fn run_command<S>(
         &mut self,
@@ -103,6 +112,61 @@ impl ADBDeviceSimpleCommand for ADBServerDevice {
     }
 }
 
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct BatteryStatus {
+    pub level: u8,
+    pub charging: bool,
+}
+
+#[derive(Debug, Clone)]
+struct DumpsysParseError {
+    output: String,
+}
+
+impl Error for DumpsysParseError {}
+
+impl std::fmt::Display for DumpsysParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "can't parse dumpsys output: {}", self.output)
+    }
+}
+
+pub(crate) trait ADBDeviceStatus {
+    fn battery_status(&mut self) -> Result<BatteryStatus, Box<dyn Error>>;
+    fn is_wifi_connected(&mut self) -> Result<bool, Box<dyn Error>>;
+    /// screen is on and not showing the lock screen
+    fn is_screen_unlocked(&mut self) -> Result<bool, Box<dyn Error>>;
+}
+
+impl ADBDeviceStatus for ADBServerDevice {
+    fn battery_status(&mut self) -> Result<BatteryStatus, Box<dyn Error>> {
+        let output = self.run_command(vec!["dumpsys", "battery"])?;
+        let level = BATTERY_LEVEL
+            .captures(&output)
+            .and_then(|caps| caps.get(1))
+            .and_then(|level| level.as_str().parse::<u8>().ok());
+        let Some(level) = level else {
+            return Err(Box::new(DumpsysParseError { output }));
+        };
+        let charging = BATTERY_CHARGING.is_match(&output);
+        Ok(BatteryStatus { level, charging })
+    }
+
+    fn is_wifi_connected(&mut self) -> Result<bool, Box<dyn Error>> {
+        let output = self.run_command(vec!["dumpsys", "wifi"])?;
+        Ok(output.contains("Supplicant state: COMPLETED"))
+    }
+
+    fn is_screen_unlocked(&mut self) -> Result<bool, Box<dyn Error>> {
+        let power = self.run_command(vec!["dumpsys", "power"])?;
+        if !power.contains("mWakefulness=Awake") {
+            return Ok(false);
+        }
+        let window = self.run_command(vec!["dumpsys", "window"])?;
+        Ok(!LOCKSCREEN_SHOWING.is_match(&window))
+    }
+}
+
 pub trait ADBServerTryConnectToDevice {
     fn try_connect_to_device(&mut self, config: &Config)
         -> Result<ADBServerDevice, Box<dyn Error>>;
@@ -0,0 +1,489 @@
+use std::{error::Error, fmt, str::FromStr};
+
+use chrono::{DateTime, Datelike, LocalResult, NaiveDate, NaiveTime, TimeZone, Weekday};
+use serde::Deserialize;
+
+/// how far ahead `next_after` looks before giving up on an expression
+const MAX_SEARCH_DAYS: u32 = 366 * 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CalendarParseError {
+    expr: String,
+    reason: String,
+}
+
+impl CalendarParseError {
+    fn new(expr: &str, reason: impl Into<String>) -> Self {
+        Self {
+            expr: expr.to_owned(),
+            reason: reason.into(),
+        }
+    }
+}
+
+impl fmt::Display for CalendarParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid calendar `{}`: {}", self.expr, self.reason)
+    }
+}
+
+impl Error for CalendarParseError {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum CalendarField {
+    Any,
+    /// sorted, deduplicated
+    Values(Vec<u32>),
+}
+
+impl CalendarField {
+    fn matches(&self, value: u32) -> bool {
+        match self {
+            CalendarField::Any => true,
+            CalendarField::Values(values) => values.binary_search(&value).is_ok(),
+        }
+    }
+
+    fn values(&self, min: u32, max: u32) -> Vec<u32> {
+        match self {
+            CalendarField::Any => (min..=max).collect(),
+            CalendarField::Values(values) => values
+                .iter()
+                .copied()
+                .filter(|v| (min..=max).contains(v))
+                .collect(),
+        }
+    }
+}
+
+/// systemd-style calendar expression, e.g. `*-*-* 04:00`, `Mon,Wed..Fri 12:30`
+/// or `daily`.
+///
+/// `[weekdays] [year-month-day | month-day] [hour:minute[:second]]`, every
+/// component accepts `*`, lists (`1,3`), ranges (`1..5`) and repetitions
+/// (`*/2`, `0..30/10`).
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Calendar {
+    source: String,
+    weekdays: CalendarField,
+    years: CalendarField,
+    months: CalendarField,
+    days: CalendarField,
+    hours: CalendarField,
+    minutes: CalendarField,
+    seconds: CalendarField,
+}
+
+impl Calendar {
+    /// first time matching the expression strictly after `after`, evaluated
+    /// in `after`'s timezone
+    pub fn next_after<Tz: TimeZone>(&self, after: &DateTime<Tz>) -> Option<DateTime<Tz>> {
+        let tz = after.timezone();
+        let start = after.naive_local();
+        let mut date = start.date();
+        for _ in 0..MAX_SEARCH_DAYS {
+            if self.date_matches(date) {
+                for time in self.times_of_day() {
+                    let dt = match tz.from_local_datetime(&date.and_time(time)) {
+                        LocalResult::Single(dt) => dt,
+                        LocalResult::Ambiguous(earliest, _) => earliest,
+                        LocalResult::None => continue,
+                    };
+                    if dt > *after {
+                        return Some(dt);
+                    }
+                }
+            }
+            date = date.succ_opt()?;
+        }
+        None
+    }
+
+    fn date_matches(&self, date: NaiveDate) -> bool {
+        let Ok(year) = u32::try_from(date.year()) else {
+            return false;
+        };
+        self.weekdays.matches(date.weekday().num_days_from_monday())
+            && self.years.matches(year)
+            && self.months.matches(date.month())
+            && self.days.matches(date.day())
+    }
+
+    fn times_of_day(&self) -> impl Iterator<Item = NaiveTime> + '_ {
+        let minutes = self.minutes.values(0, 59);
+        let seconds = self.seconds.values(0, 59);
+        self.hours.values(0, 23).into_iter().flat_map(move |h| {
+            let seconds = seconds.clone();
+            minutes.clone().into_iter().flat_map(move |m| {
+                seconds
+                    .clone()
+                    .into_iter()
+                    .filter_map(move |s| NaiveTime::from_hms_opt(h, m, s))
+            })
+        })
+    }
+}
+
+impl fmt::Display for Calendar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl FromStr for Calendar {
+    type Err = CalendarParseError;
+
+    fn from_str(expr: &str) -> Result<Self, Self::Err> {
+        let normalized = match expr.trim().to_ascii_lowercase().as_str() {
+            "minutely" => "*-*-* *:*:00",
+            "hourly" => "*-*-* *:00:00",
+            "daily" => "*-*-* 00:00:00",
+            "weekly" => "Mon *-*-* 00:00:00",
+            "monthly" => "*-*-01 00:00:00",
+            "yearly" | "annually" => "*-01-01 00:00:00",
+            _ => expr.trim(),
+        };
+
+        let mut weekdays = CalendarField::Any;
+        let mut date = None;
+        let mut time = None;
+        for (i, token) in normalized.split_whitespace().enumerate() {
+            if token.contains(':') && time.is_none() {
+                time = Some(token);
+            } else if token.contains('-') && date.is_none() && time.is_none() {
+                date = Some(token);
+            } else if i == 0 && token.starts_with(|c: char| c.is_ascii_alphabetic()) {
+                weekdays = parse_field(expr, token, 0, 6, parse_weekday)?;
+            } else {
+                return Err(CalendarParseError::new(
+                    expr,
+                    format!("unexpected `{}`", token),
+                ));
+            }
+        }
+        if date.is_none() && time.is_none() && weekdays == CalendarField::Any {
+            return Err(CalendarParseError::new(expr, "empty expression"));
+        }
+
+        let (years, months, days) = match date {
+            None => (CalendarField::Any, CalendarField::Any, CalendarField::Any),
+            Some(date) => {
+                let parts: Vec<_> = date.split('-').collect();
+                let (year, month, day) = match parts.as_slice() {
+                    [year, month, day] => (Some(*year), *month, *day),
+                    [month, day] => (None, *month, *day),
+                    _ => return Err(CalendarParseError::new(expr, "bad date")),
+                };
+                (
+                    match year {
+                        Some(year) => parse_field(expr, year, 1970, 2199, parse_number)?,
+                        None => CalendarField::Any,
+                    },
+                    parse_field(expr, month, 1, 12, parse_number)?,
+                    parse_field(expr, day, 1, 31, parse_number)?,
+                )
+            }
+        };
+
+        let (hours, minutes, seconds) = match time {
+            None => (
+                CalendarField::Values(vec![0]),
+                CalendarField::Values(vec![0]),
+                CalendarField::Values(vec![0]),
+            ),
+            Some(time) => {
+                let parts: Vec<_> = time.split(':').collect();
+                let (hour, minute, second) = match parts.as_slice() {
+                    [hour, minute] => (*hour, *minute, "00"),
+                    [hour, minute, second] => (*hour, *minute, *second),
+                    _ => return Err(CalendarParseError::new(expr, "bad time")),
+                };
+                (
+                    parse_field(expr, hour, 0, 23, parse_number)?,
+                    parse_field(expr, minute, 0, 59, parse_number)?,
+                    parse_field(expr, second, 0, 59, parse_number)?,
+                )
+            }
+        };
+
+        Ok(Self {
+            source: expr.trim().to_owned(),
+            weekdays,
+            years,
+            months,
+            days,
+            hours,
+            minutes,
+            seconds,
+        })
+    }
+}
+
+impl TryFrom<String> for Calendar {
+    type Error = CalendarParseError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Self::from_str(&value)
+    }
+}
+
+fn parse_number(s: &str) -> Option<u32> {
+    s.parse().ok()
+}
+
+fn parse_weekday(s: &str) -> Option<u32> {
+    Weekday::from_str(s)
+        .ok()
+        .map(|day| day.num_days_from_monday())
+}
+
+fn parse_field(
+    expr: &str,
+    field: &str,
+    min: u32,
+    max: u32,
+    parse_value: fn(&str) -> Option<u32>,
+) -> Result<CalendarField, CalendarParseError> {
+    if field == "*" {
+        return Ok(CalendarField::Any);
+    }
+    let parse = |s: &str| {
+        parse_value(s)
+            .filter(|v| (min..=max).contains(v))
+            .ok_or_else(|| CalendarParseError::new(expr, format!("bad value `{}`", s)))
+    };
+
+    let mut values = Vec::new();
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step =
+                    step.parse::<u32>().ok().filter(|v| *v > 0).ok_or_else(|| {
+                        CalendarParseError::new(expr, format!("bad step `{}`", step))
+                    })?;
+                (range, Some(step))
+            }
+            None => (part, None),
+        };
+        let (start, end) = match range.split_once("..") {
+            Some((start, end)) => (parse(start)?, parse(end)?),
+            None if range == "*" => (min, max),
+            None => {
+                let start = parse(range)?;
+                (start, if step.is_some() { max } else { start })
+            }
+        };
+        if end < start {
+            return Err(CalendarParseError::new(
+                expr,
+                format!("bad range `{}`", range),
+            ));
+        }
+        values.extend((start..=end).step_by(step.unwrap_or(1) as usize));
+    }
+    values.sort_unstable();
+    values.dedup();
+    Ok(CalendarField::Values(values))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{FixedOffset, NaiveDateTime};
+
+    use super::*;
+
+    fn at(s: &str) -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339(s).unwrap()
+    }
+
+    fn next(expr: &str, after: &str) -> DateTime<FixedOffset> {
+        expr.parse::<Calendar>()
+            .unwrap()
+            .next_after(&at(after))
+            .unwrap()
+    }
+
+    fn field(field: &str, min: u32, max: u32) -> Vec<u32> {
+        match parse_field(field, field, min, max, parse_number).unwrap() {
+            CalendarField::Values(values) => values,
+            CalendarField::Any => panic!("`{}` parsed as any", field),
+        }
+    }
+
+    #[test]
+    fn parses_lists_ranges_and_steps() {
+        assert_eq!(field("1,3..5,10/20", 0, 59), vec![1, 3, 4, 5, 10, 30, 50]);
+        assert_eq!(field("*/15", 0, 59), vec![0, 15, 30, 45]);
+        assert_eq!(field("0..30/10", 0, 59), vec![0, 10, 20, 30]);
+        assert_eq!(field("5,1,5", 0, 59), vec![1, 5]);
+        assert_eq!(
+            parse_field("*", "*", 0, 59, parse_number),
+            Ok(CalendarField::Any)
+        );
+    }
+
+    #[test]
+    fn parses_weekday_ranges() {
+        let calendar: Calendar = "Mon..Wed,Fri 12:00".parse().unwrap();
+        assert_eq!(calendar.weekdays, CalendarField::Values(vec![0, 1, 2, 4]));
+        assert_eq!(calendar.hours, CalendarField::Values(vec![12]));
+        assert_eq!(calendar.seconds, CalendarField::Values(vec![0]));
+    }
+
+    #[test]
+    fn parses_shortcuts() {
+        let daily: Calendar = "daily".parse().unwrap();
+        let explicit: Calendar = "*-*-* 00:00:00".parse().unwrap();
+        assert_eq!(daily.days, explicit.days);
+        assert_eq!(daily.hours, explicit.hours);
+        let weekly: Calendar = "Weekly".parse().unwrap();
+        assert_eq!(weekly.weekdays, CalendarField::Values(vec![0]));
+        assert_eq!(weekly.to_string(), "Weekly");
+    }
+
+    #[test]
+    fn rejects_bad_expressions() {
+        for expr in [
+            "",
+            "24:00",
+            "*-13-01",
+            "*-*-* 5..1:00",
+            "*:*/0",
+            "Mon foo",
+            "Funday 12:00",
+            "1-2-3-4",
+        ] {
+            assert!(expr.parse::<Calendar>().is_err(), "`{}` parsed", expr);
+        }
+    }
+
+    #[test]
+    fn next_after_is_strictly_after() {
+        assert_eq!(
+            next("04:00", "2024-05-01T04:00:00Z"),
+            at("2024-05-02T04:00:00Z")
+        );
+        assert_eq!(
+            next("04:00", "2024-05-01T03:59:59Z"),
+            at("2024-05-01T04:00:00Z")
+        );
+    }
+
+    #[test]
+    fn next_after_weekdays_and_dates() {
+        // 2024-05-07 is a Tuesday
+        assert_eq!(
+            next("Mon,Wed..Fri 12:30", "2024-05-07T13:00:00Z"),
+            at("2024-05-08T12:30:00Z")
+        );
+        assert_eq!(
+            next("weekly", "2024-05-07T13:00:00Z"),
+            at("2024-05-13T00:00:00Z")
+        );
+        assert_eq!(
+            next("*-02-29 00:00", "2025-01-01T00:00:00Z"),
+            at("2028-02-29T00:00:00Z")
+        );
+        assert_eq!(
+            next("monthly", "2024-12-15T00:00:00Z"),
+            at("2025-01-01T00:00:00Z")
+        );
+        assert_eq!(
+            next("*:*:30", "2024-05-01T10:00:30Z"),
+            at("2024-05-01T10:01:30Z")
+        );
+    }
+
+    #[test]
+    fn next_after_uses_the_timezone_of_after() {
+        assert_eq!(
+            next("04:00", "2024-05-01T05:00:00+09:00"),
+            at("2024-05-02T04:00:00+09:00")
+        );
+    }
+
+    /// UTC+1 that skips 02:00..03:00 local on 2024-03-31, moving to UTC+2
+    #[derive(Clone, Copy, Debug)]
+    struct SpringForward;
+
+    impl SpringForward {
+        fn switch_local() -> NaiveDateTime {
+            NaiveDate::from_ymd_opt(2024, 3, 31)
+                .unwrap()
+                .and_hms_opt(2, 0, 0)
+                .unwrap()
+        }
+
+        fn before() -> FixedOffset {
+            FixedOffset::east_opt(3600).unwrap()
+        }
+
+        fn after() -> FixedOffset {
+            FixedOffset::east_opt(2 * 3600).unwrap()
+        }
+    }
+
+    impl TimeZone for SpringForward {
+        type Offset = FixedOffset;
+
+        fn from_offset(_: &FixedOffset) -> Self {
+            SpringForward
+        }
+
+        fn offset_from_local_date(&self, local: &NaiveDate) -> LocalResult<FixedOffset> {
+            self.offset_from_local_datetime(&local.and_time(NaiveTime::MIN))
+        }
+
+        fn offset_from_local_datetime(&self, local: &NaiveDateTime) -> LocalResult<FixedOffset> {
+            let switch = Self::switch_local();
+            if *local < switch {
+                LocalResult::Single(Self::before())
+            } else if *local < switch + chrono::Duration::hours(1) {
+                LocalResult::None
+            } else {
+                LocalResult::Single(Self::after())
+            }
+        }
+
+        fn offset_from_utc_date(&self, utc: &NaiveDate) -> FixedOffset {
+            self.offset_from_utc_datetime(&utc.and_time(NaiveTime::MIN))
+        }
+
+        fn offset_from_utc_datetime(&self, utc: &NaiveDateTime) -> FixedOffset {
+            if *utc < Self::switch_local() - chrono::Duration::hours(1) {
+                Self::before()
+            } else {
+                Self::after()
+            }
+        }
+    }
+
+    #[test]
+    fn next_after_skips_times_in_a_dst_gap() {
+        let calendar: Calendar = "02:30".parse().unwrap();
+        let after = SpringForward
+            .with_ymd_and_hms(2024, 3, 30, 12, 0, 0)
+            .unwrap();
+        let next = calendar.next_after(&after).unwrap();
+        assert_eq!(
+            next.naive_local(),
+            NaiveDate::from_ymd_opt(2024, 4, 1)
+                .unwrap()
+                .and_hms_opt(2, 30, 0)
+                .unwrap()
+        );
+
+        let calendar: Calendar = "*:30".parse().unwrap();
+        let after = SpringForward
+            .with_ymd_and_hms(2024, 3, 31, 1, 45, 0)
+            .unwrap();
+        assert_eq!(
+            calendar.next_after(&after).unwrap().naive_local(),
+            NaiveDate::from_ymd_opt(2024, 3, 31)
+                .unwrap()
+                .and_hms_opt(3, 30, 0)
+                .unwrap()
+        );
+    }
+}
@@ -9,9 +9,10 @@ use std::{
 use chrono::Local;
 use eframe::egui;
 use image::{DynamicImage, ImageBuffer};
-use image_new::DynamicImage as DynamicImageNew;
 use winit::platform::windows::EventLoopBuilderExtWindows;
 
+use crate::plan_engine::IdentResult;
+
 pub fn run() -> Result<Arc<Mutex<DebugData>>, Box<dyn std::error::Error>> {
     let mut data = DebugData::default();
     data.items
@@ -70,22 +71,24 @@ impl DebugData {
         path
     }
 
+    pub fn push_ident(&mut self, screen: &str, result: &IdentResult) {
+        self.items
+            .push(DebugItem::Ident(screen.to_string(), result.clone()));
+    }
+
     pub fn push_image(&mut self, image: DynamicImage) {
         let path = self.get_next_image_path();
         image.to_rgba8().save(&path).unwrap();
         self.items.push(DebugItem::Image(path));
     }
-    pub fn push_image_new(&mut self, image: DynamicImageNew) {
-        let path = self.get_next_image_path();
-        image.save(&path).unwrap();
-        self.items.push(DebugItem::Image(path));
-    }
 }
 
 #[derive(Debug)]
 pub enum DebugItem {
     Text(String),
     Image(PathBuf),
+    /// an ident checked for a screen or popup
+    Ident(String, IdentResult),
 }
 
 impl eframe::App for MyApp {
@@ -109,6 +112,14 @@ impl eframe::App for MyApp {
                             DebugItem::Text(text) => {
                                 ui.label(text);
                             }
+                            DebugItem::Ident(screen, result) => {
+                                let color = if result.matched {
+                                    egui::Color32::GREEN
+                                } else {
+                                    egui::Color32::RED
+                                };
+                                ui.colored_label(color, format!("{}: {}", screen, result));
+                            }
                             DebugItem::Image(path) => {
                                 let img_widget =
                                     egui::Image::new(format!("file://{}", path.to_string_lossy()))
@@ -5,15 +5,25 @@ use std::{
     net::{AddrParseError, Ipv4Addr, SocketAddrV4},
     path::{Path, PathBuf},
     str::FromStr,
+    time::Duration,
 };
 
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, NaiveTime, TimeDelta, Timelike};
+use regex::Regex;
 use serde::{Deserialize, Deserializer};
 
+use crate::{calendar::Calendar, image_stuff::TemplateCache};
+
 /// `(x, y)`
 type Pos = (u32, u32);
 /// `(x, y, width, height)`
 type Rect = (u32, u32, u32, u32);
 
+/// normalized squared difference below which an image ident matches
+const DEFAULT_MATCH_THRESHOLD: f32 = 0.04;
+/// pixels around an image ident's expected position that are searched
+const DEFAULT_MATCH_MARGIN: u32 = 20;
+
 #[derive(Clone, Deserialize, Debug)]
 pub struct Config {
     pub adb: AdbConfig,
@@ -79,8 +89,24 @@ pub struct Plan {
     pub activity: String,
     pub screens: HashMap<String, Screen>,
     pub screen_groups: HashMap<String, ScreenGroup>,
+    pub popups: HashMap<String, Popup>,
     pub schedules: Vec<Schedule>,
     pub routine_location: HashMap<PathBuf, String>,
+    pub routines: HashMap<PathBuf, RoutineSettings>,
+    /// game server timezone, host local time when unset
+    pub timezone: Option<FixedOffset>,
+    /// time of day the game day rolls over, in `timezone`
+    pub reset_time: NaiveTime,
+    /// default `threshold` of image idents
+    pub match_threshold: f32,
+    /// default `margin` of image idents
+    pub match_margin: u32,
+    pub templates: TemplateCache,
+    /// `(width, height)` every coordinate in the plan is relative to, the
+    /// device's own resolution when unset
+    pub design_resolution: Option<(u32, u32)>,
+    /// patterns of the plan's `regex` OCR idents, compiled
+    pub regexes: HashMap<String, Regex>,
 }
 
 #[derive(Clone, Debug)]
@@ -99,6 +125,51 @@ pub struct ScreenGroup {
 }
 
 impl Plan {
+    /// next firing of `calendar` after `after`, evaluated in the plan's timezone
+    pub fn next_firing(
+        &self,
+        calendar: &Calendar,
+        after: &DateTime<Local>,
+    ) -> Option<DateTime<Local>> {
+        match self.timezone {
+            Some(tz) => calendar
+                .next_after(&after.with_timezone(&tz))
+                .map(|dt| dt.with_timezone(&Local)),
+            None => calendar.next_after(after),
+        }
+    }
+
+    /// the game day `at` belongs to, days start at `reset_time`
+    pub fn game_day(&self, at: &DateTime<Local>) -> NaiveDate {
+        let local = match self.timezone {
+            Some(tz) => at.with_timezone(&tz).naive_local(),
+            None => at.naive_local(),
+        };
+        let reset = TimeDelta::seconds(self.reset_time.num_seconds_from_midnight().into());
+        (local - reset).date()
+    }
+
+    pub fn routine_settings(&self, routine: &Path) -> RoutineSettings {
+        self.routines.get(routine).cloned().unwrap_or_default()
+    }
+
+    /// `routines` reordered so every routine comes after the ones it depends
+    /// on in the same list, otherwise in the listed order
+    pub fn ordered_routines<'r>(&self, routines: &'r [PathBuf]) -> Vec<&'r PathBuf> {
+        let mut remaining: Vec<_> = routines.iter().collect();
+        let mut ordered = Vec::with_capacity(routines.len());
+        while !remaining.is_empty() {
+            let ready = remaining.iter().position(|routine| {
+                self.routine_settings(routine)
+                    .dependencies()
+                    .all(|dep| !remaining.contains(&dep))
+            });
+            // dependency cycles fall back to the listed order
+            ordered.push(remaining.remove(ready.unwrap_or(0)));
+        }
+        ordered
+    }
+
     pub fn validate(mut self) -> Result<(Self, Vec<String>), Box<dyn Error>> {
         let mut warnings = Vec::new();
         let screen_names: HashSet<_> = self.screens.keys().map(|v| v.to_owned()).collect();
@@ -126,6 +197,74 @@ impl Plan {
                 keep
             });
         }
+        for (name, popup) in &self.popups {
+            if screen_names.contains(name) {
+                return Err(format!("popup `{}` has the same name as a screen", name).into());
+            }
+            if popup.ident.is_empty() {
+                return Err(format!("popup `{}` has no ident", name).into());
+            }
+            for screen in popup.on.iter().filter(|s| !screen_names.contains(*s)) {
+                warnings.push(format!(
+                    "popup `{}` shows up on a non-existent screen `{}`",
+                    name, screen
+                ));
+            }
+        }
+        for (i, schedule) in self.schedules.iter_mut().enumerate() {
+            if let Some(start_at) = &schedule.start_at {
+                if !screen_names.contains(start_at) {
+                    warnings.push(format!(
+                        "schedule {} starts at a non-existent screen `{}`",
+                        i, start_at
+                    ));
+                    schedule.start_at = None;
+                }
+            }
+        }
+        if let Some((width, height)) = self.design_resolution {
+            if width == 0 || height == 0 {
+                warnings.push(format!(
+                    "design resolution {}x{} is empty, using the device's",
+                    width, height
+                ));
+                self.design_resolution = None;
+            }
+        }
+        let mut patterns = Vec::new();
+        let idents = self
+            .screens
+            .values()
+            .flat_map(|screen| &screen.ident)
+            .chain(self.screen_groups.values().flat_map(|group| &group.ident))
+            .chain(self.popups.values().flat_map(|popup| &popup.ident));
+        for ident in idents {
+            ident.walk(&mut |ident| {
+                if let ScreenIdent::Ocr {
+                    ocr,
+                    operation: TextOperation::Regex,
+                    ..
+                } = ident
+                {
+                    patterns.push(ocr.clone());
+                }
+            });
+        }
+        for pattern in patterns {
+            let regex = Regex::new(&pattern)
+                .map_err(|err| format!("invalid OCR regex `{}`: {}", pattern, err))?;
+            self.regexes.insert(pattern, regex);
+        }
+        for (routine, settings) in &self.routines {
+            for dep in settings.dependencies() {
+                if !self.routine_location.contains_key(dep) && !self.routines.contains_key(dep) {
+                    warnings.push(format!(
+                        "routine {:?} depends on unknown routine {:?}",
+                        routine, dep
+                    ));
+                }
+            }
+        }
         Ok((self, warnings))
     }
     pub fn new(plan_wd: &Path) -> Result<(Self, Vec<String>), Box<dyn std::error::Error>> {
@@ -196,8 +335,17 @@ impl Plan {
             activity: plan.activity,
             screens,
             screen_groups,
+            popups: plan.popups,
             schedules: plan.schedules,
             routine_location,
+            routines: plan.routines,
+            timezone: plan.timezone,
+            reset_time: plan.reset_time.unwrap_or_default(),
+            match_threshold: plan.match_threshold.unwrap_or(DEFAULT_MATCH_THRESHOLD),
+            match_margin: plan.match_margin.unwrap_or(DEFAULT_MATCH_MARGIN),
+            templates: TemplateCache::default(),
+            design_resolution: plan.design_resolution,
+            regexes: HashMap::new(),
         };
         Ok(plan.validate()?)
     }
@@ -210,7 +358,64 @@ pub struct PlanDef {
     #[serde(default)]
     pub screens: HashMap<String, ScreenDef>,
     #[serde(default)]
+    pub popups: HashMap<String, Popup>,
+    #[serde(default)]
     pub schedules: Vec<Schedule>,
+    /// fixed UTC offset like `"+09:00"`
+    #[serde(default, deserialize_with = "deserialize_opt_from_str")]
+    pub timezone: Option<FixedOffset>,
+    /// like `"04:00"`
+    #[serde(default, deserialize_with = "deserialize_opt_from_str")]
+    pub reset_time: Option<NaiveTime>,
+    pub match_threshold: Option<f32>,
+    pub match_margin: Option<u32>,
+    /// like `[1280, 720]`
+    pub design_resolution: Option<(u32, u32)>,
+    /// retry settings keyed by routine path
+    #[serde(default)]
+    pub routines: HashMap<PathBuf, RoutineSettings>,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct RoutineSettings {
+    /// tries before the routine counts as failed
+    #[serde(default = "default_attempts")]
+    pub attempts: u32,
+    /// wait after the first failed attempt, doubled after every further one
+    #[serde(default, deserialize_with = "deserialize_opt_duration")]
+    pub backoff: Option<Duration>,
+    /// restart the app before trying again
+    #[serde(default)]
+    pub restart_app: bool,
+    /// routines that must have succeeded earlier in the same game day
+    #[serde(default)]
+    pub requires: Vec<PathBuf>,
+    /// routines that must have run earlier in the same game day, successful
+    /// or not
+    #[serde(default)]
+    pub after: Vec<PathBuf>,
+}
+
+impl RoutineSettings {
+    pub fn dependencies(&self) -> impl Iterator<Item = &PathBuf> {
+        self.requires.iter().chain(&self.after)
+    }
+}
+
+impl Default for RoutineSettings {
+    fn default() -> Self {
+        Self {
+            attempts: default_attempts(),
+            backoff: None,
+            restart_app: false,
+            requires: Vec::new(),
+            after: Vec::new(),
+        }
+    }
+}
+
+fn default_attempts() -> u32 {
+    1
 }
 
 #[derive(Clone, Deserialize, Debug)]
@@ -224,6 +429,17 @@ pub struct ScreenDef {
     pub subscreens: Option<HashMap<String, Subscreen>>,
 }
 
+/// an overlay like a login bonus or event banner that may cover screens
+#[derive(Clone, Deserialize, Debug)]
+pub struct Popup {
+    #[serde(deserialize_with = "deserialize_single_or_vec")]
+    pub ident: Vec<ScreenIdent>,
+    pub dismiss: ScreenTo,
+    /// screens the popup shows up on, any screen when empty
+    #[serde(default)]
+    pub on: Vec<String>,
+}
+
 #[derive(Clone, Deserialize, Debug)]
 pub struct ScreenNavigation {
     #[serde(default)]
@@ -249,16 +465,140 @@ pub enum ScreenIdent {
         #[serde(rename = "ref")]
         reference: PathBuf,
         rect: Rect,
+        /// highest normalized difference that still matches, the plan's
+        /// `match_threshold` when unset
+        threshold: Option<f32>,
+        /// pixels around `rect` to search, the plan's `match_margin` when unset
+        margin: Option<u32>,
+        #[serde(default)]
+        color: ColorMode,
     },
     ImageMatch {
         image: PathBuf,
         pos: Pos,
+        /// highest normalized difference that still matches, the plan's
+        /// `match_threshold` when unset
+        threshold: Option<f32>,
+        /// pixels around `pos` to search, the plan's `match_margin` when unset
+        margin: Option<u32>,
+        #[serde(default)]
+        color: ColorMode,
     },
     Ocr {
         ocr: String,
         operation: TextOperation,
         rect: Rect,
+        #[serde(default)]
+        preprocess: OcrPreprocess,
     },
+    /// compares the number read in `rect`, e.g. `{ number = ">=", value = 40, rect = ... }`
+    OcrNumber {
+        #[serde(rename = "number")]
+        comparison: Comparison,
+        value: f64,
+        rect: Rect,
+        /// which number of an `a/b` fraction to compare
+        #[serde(default)]
+        part: FractionPart,
+        #[serde(default)]
+        preprocess: OcrPreprocess,
+    },
+    Pixel {
+        #[serde(deserialize_with = "deserialize_single_or_vec")]
+        pixel: Vec<PixelColor>,
+        /// largest difference allowed in any channel
+        #[serde(default = "default_pixel_tolerance")]
+        tolerance: u8,
+    },
+    /// matches if any of the idents does
+    Any {
+        #[serde(deserialize_with = "deserialize_single_or_vec")]
+        any: Vec<ScreenIdent>,
+    },
+    /// matches if every ident does, like a screen's own `ident` list
+    All {
+        #[serde(deserialize_with = "deserialize_single_or_vec")]
+        all: Vec<ScreenIdent>,
+    },
+    /// matches if the ident doesn't
+    Not { not: Box<ScreenIdent> },
+}
+
+impl ScreenIdent {
+    /// calls `f` with this ident and every ident nested in it
+    pub fn walk(&self, f: &mut impl FnMut(&ScreenIdent)) {
+        f(self);
+        match self {
+            ScreenIdent::Any { any: idents } | ScreenIdent::All { all: idents } => {
+                for ident in idents {
+                    ident.walk(f);
+                }
+            }
+            ScreenIdent::Not { not } => not.walk(f),
+            _ => {}
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct PixelColor {
+    pub pos: Pos,
+    pub rgb: [u8; 3],
+}
+
+fn default_pixel_tolerance() -> u8 {
+    10
+}
+
+/// cleanup of an OCR area before it's read, e.g.
+/// `preprocess = { upscale = 2, threshold = "otsu" }`. Steps run in the order
+/// of the fields
+#[derive(Clone, Deserialize, Debug, Default)]
+pub struct OcrPreprocess {
+    /// factor to scale the area up by, small text reads better bigger
+    pub upscale: Option<f32>,
+    /// keeps only the pixels close to a color, as black text on white
+    pub color_key: Option<ColorKey>,
+    #[serde(default)]
+    pub grayscale: bool,
+    /// turns the area black and white, implies `grayscale`
+    pub threshold: Option<OcrThreshold>,
+    #[serde(default)]
+    pub invert: bool,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct ColorKey {
+    pub rgb: [u8; 3],
+    /// largest difference allowed in any channel
+    #[serde(default = "default_color_key_tolerance")]
+    pub tolerance: u8,
+}
+
+pub fn default_color_key_tolerance() -> u8 {
+    40
+}
+
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OcrThreshold {
+    /// picks the level that best splits the area's brightness
+    Otsu,
+    /// brightness above which a pixel turns white, e.g. `{ fixed = 128 }`
+    Fixed(u8),
+}
+
+/// what image idents compare
+#[derive(Clone, Copy, Deserialize, Debug, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum ColorMode {
+    /// brightness only
+    #[default]
+    Luma,
+    /// red, green and blue separately
+    Rgb,
+    /// CIELAB, so differences in hue count about as much as they're seen
+    Lab,
 }
 
 #[derive(Clone, Deserialize, Debug)]
@@ -268,6 +608,45 @@ pub enum TextOperation {
     Contains,
     StartsWith,
     EndsWith,
+    /// the target is a regular expression searched for in the text
+    Regex,
+    /// the text is close to the target, e.g. `{ fuzzy = { max_distance = 2 } }`
+    Fuzzy(FuzzyMatch),
+}
+
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq, Eq)]
+pub enum Comparison {
+    #[serde(rename = "==")]
+    Eq,
+    #[serde(rename = "!=")]
+    Ne,
+    #[serde(rename = "<")]
+    Lt,
+    #[serde(rename = "<=")]
+    Le,
+    #[serde(rename = ">")]
+    Gt,
+    #[serde(rename = ">=")]
+    Ge,
+}
+
+#[derive(Clone, Copy, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum FractionPart {
+    /// also the number itself when there's no fraction
+    #[default]
+    Numerator,
+    Denominator,
+}
+
+/// bounds on how far OCR text may be from the target, every bound that is set
+/// has to hold. Without any, one edit is allowed
+#[derive(Clone, Deserialize, Debug)]
+pub struct FuzzyMatch {
+    /// most single character insertions, deletions or substitutions
+    pub max_distance: Option<usize>,
+    /// least `1 - distance / length of the longer text`
+    pub min_ratio: Option<f32>,
 }
 
 #[derive(Clone, Deserialize, Debug)]
@@ -288,11 +667,61 @@ pub enum Actions {
 
 #[derive(Clone, Deserialize, Debug)]
 pub struct Schedule {
+    /// stable identifier for run history, defaults to the schedule's index
+    pub name: Option<String>,
     #[serde(flatten)]
     pub action: ScheduleActions,
-    pub on_calendar: String,
+    pub on_calendar: Calendar,
     #[serde(default)]
     pub interruptible: bool,
+    /// due schedules with a higher priority run first and may interrupt
+    /// running `interruptible` ones
+    #[serde(default)]
+    pub priority: i32,
+    /// screen to navigate to before running a `script` schedule, without it
+    /// the script navigates by itself
+    pub start_at: Option<String>,
+    /// what to do with firings missed while the host or device was down
+    #[serde(default)]
+    pub catch_up: CatchUp,
+    /// firings later than this are dropped, e.g. `"90m"` or `"1h30m"`
+    #[serde(default, deserialize_with = "deserialize_opt_duration")]
+    pub max_lateness: Option<Duration>,
+    /// device state checked before the app is started, e.g.
+    /// `device = { min_battery = 30, wifi = true }`. The schedule is deferred
+    /// while any of it is unmet
+    #[serde(default)]
+    pub device: DeviceRequirements,
+}
+
+#[derive(Clone, Deserialize, Debug, Default)]
+pub struct DeviceRequirements {
+    /// battery percentage
+    pub min_battery: Option<u8>,
+    #[serde(default)]
+    pub charging: bool,
+    #[serde(default)]
+    pub wifi: bool,
+    #[serde(default)]
+    pub unlocked: bool,
+}
+
+impl Schedule {
+    pub fn history_key(&self, index: usize) -> String {
+        self.name.clone().unwrap_or_else(|| index.to_string())
+    }
+}
+
+#[derive(Clone, Copy, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CatchUp {
+    /// only run firings that are on time
+    Skip,
+    /// run the latest missed firing once
+    #[default]
+    Once,
+    /// run every missed firing
+    All,
 }
 
 #[derive(Clone, Deserialize, Debug)]
@@ -326,6 +755,57 @@ where
     Ok(Vec::<T>::from(SingleOrVec::deserialize(deserializer)?))
 }
 
+/// parses durations like `"45s"`, `"15m"`, `"2h"`, `"1d"` or `"1h30m"`
+pub fn parse_duration(str: &str) -> Option<Duration> {
+    if str.trim().is_empty() {
+        return None;
+    }
+    let mut total = 0u64;
+    let mut number = String::new();
+    for c in str.trim().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => return None,
+        };
+        let secs = number.parse::<u64>().ok()?.checked_mul(unit)?;
+        total = total.checked_add(secs)?;
+        number.clear();
+    }
+    if !number.is_empty() {
+        return None;
+    }
+    Some(Duration::from_secs(total))
+}
+
+fn deserialize_opt_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let str = String::deserialize(deserializer)?;
+    parse_duration(&str)
+        .map(Some)
+        .ok_or_else(|| serde::de::Error::custom(format!("invalid duration `{}`", str)))
+}
+
+fn deserialize_opt_from_str<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    let str = String::deserialize(deserializer)?;
+    T::from_str(&str)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
 pub fn deser_idents(str: &str) -> Result<Vec<ScreenIdent>, Box<dyn Error>> {
     let s_or_v = deserialize_single_or_vec(toml::de::ValueDeserializer::new(str))?;
     Ok(s_or_v)
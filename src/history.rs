@@ -0,0 +1,191 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Local, NaiveDate};
+use serde::{Deserialize, Serialize};
+
+use crate::def::Plan;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RunOutcome {
+    /// started but never finished, e.g. the process crashed mid-run
+    Running,
+    Success,
+    Failed,
+    /// prerequisites of the routine weren't met. A schedule is skipped when
+    /// any of its routines was and none failed
+    Skipped,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ScheduleRecord {
+    /// the calendar time this run was scheduled for
+    pub firing: DateTime<Local>,
+    /// the plan's game day of `firing`
+    #[serde(default)]
+    pub game_day: Option<NaiveDate>,
+    pub started: DateTime<Local>,
+    pub finished: Option<DateTime<Local>>,
+    pub outcome: RunOutcome,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RoutineRecord {
+    pub game_day: NaiveDate,
+    pub finished: DateTime<Local>,
+    pub outcome: RunOutcome,
+    pub attempts: u32,
+    /// error of the last failed attempt, or why the routine was skipped
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct PlanHistory {
+    #[serde(default)]
+    schedules: HashMap<String, ScheduleRecord>,
+    /// keyed by routine path
+    #[serde(default)]
+    routines: HashMap<String, RoutineRecord>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct HistoryFile {
+    #[serde(default)]
+    plans: HashMap<String, PlanHistory>,
+}
+
+/// last run of every (plan, schedule) and (plan, routine), persisted to disk after every change
+#[derive(Clone, Debug)]
+pub struct RunHistory {
+    path: PathBuf,
+    data: HistoryFile,
+}
+
+impl RunHistory {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let data = match fs::read_to_string(path) {
+            Ok(str) => toml::from_str(&str)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HistoryFile::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path: path.to_owned(),
+            data,
+        })
+    }
+
+    fn save(&self) -> Result<(), Box<dyn Error>> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp_path = self.path.with_extension("toml.tmp");
+        fs::write(&tmp_path, toml::to_string(&self.data)?)?;
+        fs::rename(tmp_path, &self.path)?;
+        Ok(())
+    }
+
+    fn plan_key(plan: &Plan) -> String {
+        plan.workdir.to_string_lossy().into_owned()
+    }
+
+    pub fn schedule(&self, plan: &Plan, schedule_key: &str) -> Option<&ScheduleRecord> {
+        self.data
+            .plans
+            .get(&Self::plan_key(plan))?
+            .schedules
+            .get(schedule_key)
+    }
+
+    pub fn record_start(
+        &mut self,
+        plan: &Plan,
+        schedule_key: &str,
+        firing: DateTime<Local>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.data
+            .plans
+            .entry(Self::plan_key(plan))
+            .or_default()
+            .schedules
+            .insert(
+                schedule_key.to_owned(),
+                ScheduleRecord {
+                    firing,
+                    game_day: Some(plan.game_day(&firing)),
+                    started: Local::now(),
+                    finished: None,
+                    outcome: RunOutcome::Running,
+                },
+            );
+        self.save()
+    }
+
+    pub fn record_finish(
+        &mut self,
+        plan: &Plan,
+        schedule_key: &str,
+        outcome: RunOutcome,
+    ) -> Result<(), Box<dyn Error>> {
+        let record = self
+            .data
+            .plans
+            .get_mut(&Self::plan_key(plan))
+            .and_then(|p| p.schedules.get_mut(schedule_key));
+        if let Some(record) = record {
+            record.finished = Some(Local::now());
+            record.outcome = outcome;
+        }
+        self.save()
+    }
+
+    pub fn routine(&self, plan: &Plan, routine: &Path) -> Option<&RoutineRecord> {
+        self.data
+            .plans
+            .get(&Self::plan_key(plan))?
+            .routines
+            .get(routine.to_string_lossy().as_ref())
+    }
+
+    /// records how `routine` went, except that a skip doesn't replace a
+    /// success from the same game day
+    pub fn record_routine(
+        &mut self,
+        plan: &Plan,
+        routine: &Path,
+        outcome: RunOutcome,
+        attempts: u32,
+        error: Option<String>,
+    ) -> Result<(), Box<dyn Error>> {
+        let now = Local::now();
+        let game_day = plan.game_day(&now);
+        let routines = &mut self
+            .data
+            .plans
+            .entry(Self::plan_key(plan))
+            .or_default()
+            .routines;
+        let key = routine.to_string_lossy().into_owned();
+        let succeeded_today = routines.get(&key).is_some_and(|record| {
+            record.game_day == game_day && record.outcome == RunOutcome::Success
+        });
+        if outcome == RunOutcome::Skipped && succeeded_today {
+            return Ok(());
+        }
+        routines.insert(
+            key,
+            RoutineRecord {
+                game_day,
+                finished: now,
+                outcome,
+                attempts,
+                error,
+            },
+        );
+        self.save()
+    }
+}
@@ -1,5 +1,18 @@
-use image::{ImageBuffer, RgbaImage, GrayImage, Luma};
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use image::{imageops::FilterType, io::Reader as ImageReader, DynamicImage, ImageBuffer, RgbaImage, GrayImage, Luma};
 pub use image_new::RgbaImage as RgbaImageNew;
+use imageproc::contrast::{otsu_level, threshold};
+
+use crate::def::{ColorKey, ColorMode, OcrPreprocess, OcrThreshold};
+
+pub type LumaF32Image = ImageBuffer<Luma<f32>, Vec<f32>>;
 
 
 
@@ -19,4 +32,132 @@ pub fn convert_luma_f32_to_u8(image: ImageBuffer<Luma<f32>, Vec<f32>>, max_value
         let scaled_value = ((pixel_value / max_value) * 255.0) as u8;
         Luma([scaled_value])
     })
-}
\ No newline at end of file
+}
+
+/// the planes of `image` that `mode` compares, each roughly in `0.0..=1.0`
+pub fn color_planes(image: &DynamicImage, mode: ColorMode) -> Vec<LumaF32Image> {
+    if mode == ColorMode::Luma {
+        return vec![image.to_luma32f()];
+    }
+    let rgb = image.to_rgb32f();
+    let (width, height) = rgb.dimensions();
+    let mut planes = vec![ImageBuffer::new(width, height); 3];
+    for (x, y, pixel) in rgb.enumerate_pixels() {
+        let values = match mode {
+            ColorMode::Lab => {
+                let [l, a, b] = srgb_to_lab(pixel.0);
+                [l / 100.0, (a + 128.0) / 255.0, (b + 128.0) / 255.0]
+            }
+            _ => pixel.0,
+        };
+        for (plane, value) in planes.iter_mut().zip(values) {
+            plane.put_pixel(x, y, Luma([value]));
+        }
+    }
+    planes
+}
+
+/// CIELAB under D65, `L` in `0.0..=100.0`, `a` and `b` about `-128.0..=127.0`
+fn srgb_to_lab(rgb: [f32; 3]) -> [f32; 3] {
+    let [r, g, b] = rgb.map(|c| {
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    });
+    let x = (0.4124564 * r + 0.3575761 * g + 0.1804375 * b) / 0.95047;
+    let y = 0.2126729 * r + 0.7151522 * g + 0.0721750 * b;
+    let z = (0.0193339 * r + 0.119192 * g + 0.9503041 * b) / 1.08883;
+    let f = |t: f32| {
+        const DELTA: f32 = 6.0 / 29.0;
+        if t > DELTA.powi(3) {
+            t.cbrt()
+        } else {
+            t / (3.0 * DELTA * DELTA) + 4.0 / 29.0
+        }
+    };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+/// runs the steps of `preprocess` on an area about to be read by OCR
+pub fn preprocess_ocr(mut image: DynamicImage, preprocess: &OcrPreprocess) -> DynamicImage {
+    if let Some(factor) = preprocess.upscale.filter(|f| *f > 0.0 && *f != 1.0) {
+        let width = (image.width() as f32 * factor).round().max(1.0) as u32;
+        let height = (image.height() as f32 * factor).round().max(1.0) as u32;
+        image = image.resize_exact(width, height, FilterType::CatmullRom);
+    }
+    if let Some(ColorKey { rgb, tolerance }) = &preprocess.color_key {
+        let colors = image.to_rgb8();
+        let keyed = GrayImage::from_fn(colors.width(), colors.height(), |x, y| {
+            let actual = colors.get_pixel(x, y).0;
+            let close = rgb
+                .iter()
+                .zip(actual)
+                .all(|(expected, actual)| expected.abs_diff(actual) <= *tolerance);
+            Luma([if close { 0 } else { 255 }])
+        });
+        image = DynamicImage::ImageLuma8(keyed);
+    }
+    if preprocess.grayscale || preprocess.threshold.is_some() {
+        image = DynamicImage::ImageLuma8(image.to_luma8());
+    }
+    if let Some(level) = preprocess.threshold {
+        let gray = image.to_luma8();
+        let level = match level {
+            OcrThreshold::Otsu => otsu_level(&gray),
+            OcrThreshold::Fixed(level) => level,
+        };
+        image = DynamicImage::ImageLuma8(threshold(&gray, level));
+    }
+    if preprocess.invert {
+        image.invert();
+    }
+    image
+}
+
+/// a reference image, decoded and split into the planes of its color mode
+pub struct Template {
+    pub image: DynamicImage,
+    pub planes: Vec<LumaF32Image>,
+}
+
+type TemplateKey = (PathBuf, Option<(u32, u32, u32, u32)>, ColorMode);
+
+/// templates by path, crop rect and color mode. Clones share the cache, a
+/// reloaded plan starts with an empty one
+#[derive(Clone, Default)]
+pub struct TemplateCache {
+    templates: Arc<Mutex<HashMap<TemplateKey, Arc<Template>>>>,
+}
+
+impl TemplateCache {
+    pub fn get(
+        &self,
+        path: &Path,
+        crop: Option<(u32, u32, u32, u32)>,
+        mode: ColorMode,
+    ) -> Result<Arc<Template>, Box<dyn Error>> {
+        let key = (path.to_owned(), crop, mode);
+        if let Some(template) = self.templates.lock().unwrap().get(&key) {
+            return Ok(template.clone());
+        }
+        let mut image = ImageReader::open(path)?.decode()?;
+        if let Some((x, y, width, height)) = crop {
+            image = image.crop_imm(x, y, width, height);
+        }
+        let template = Arc::new(Template {
+            planes: color_planes(&image, mode),
+            image,
+        });
+        self.templates.lock().unwrap().insert(key, template.clone());
+        Ok(template)
+    }
+}
+
+impl fmt::Debug for TemplateCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TemplateCache({} templates)", self.templates.lock().unwrap().len())
+    }
+}
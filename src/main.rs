@@ -7,18 +7,18 @@ use std::{
     path::{Path, PathBuf},
     process::Command,
     str::FromStr,
-    sync::{Arc, Mutex, Weak},
+    sync::{Arc, Mutex},
     thread::sleep,
     time::Duration,
     vec::{self, Vec},
 };
 
-use adb_client::{ADBServer, ADBServerDevice};
-use adb_device_ext::{ADBDeviceSimpleCommand, ADBServerTryConnectToDevice};
+use adb_client::ADBServer;
+use adb_device_ext::ADBServerTryConnectToDevice;
 use chrono::Local;
-use debug_gui::DebugData;
-use def::{Config, Plan, Schedule};
+use def::{Config, Plan};
 use glob::glob;
+use history::RunHistory;
 use image::{io::Reader as ImageReader, DynamicImage, GrayImage, ImageBuffer, Luma, RgbaImage};
 use image_stuff::{convert_luma_f32_to_u8, downgrade_image};
 use imageproc::contrast::{otsu_level, threshold};
@@ -28,16 +28,39 @@ use ocrs::{ImageSource, OcrEngine, OcrEngineParams};
 use plan_engine::ScreenEngine;
 use regex::Regex;
 use rten::Model;
+use runner::Runner;
 use serde::Deserialize;
 use template_matching::{find_extremes, match_template, MatchTemplateMethod};
 mod adb_device_ext;
+mod calendar;
 mod debug_gui;
 mod def;
+mod history;
 mod image_stuff;
 mod plan_engine;
+mod runner;
+mod scheduler;
+mod viewport;
+
+/// upper bound for a single sleep so a suspended host notices the clock moved
+const MAX_SCHEDULER_SLEEP: Duration = Duration::from_secs(60);
+
+/// firings listed per schedule by `plan-a preview` without a count
+const DEFAULT_PREVIEW_COUNT: usize = 5;
 
 fn main() -> Result<(), Box<dyn Error>> {
     let userdata_path = Path::new("./userdata"); // TODO
+
+    let args = std::env::args().collect::<Vec<_>>();
+    if args.get(1).map(String::as_str) == Some("preview") {
+        let count = match args.get(2) {
+            Some(count) => count.parse()?,
+            None => DEFAULT_PREVIEW_COUNT,
+        };
+        print_preview(&load_plans(userdata_path), count);
+        return Ok(());
+    }
+
     let config = def::Config::new(&userdata_path.join("config.toml"))?;
 
     let debug_gui = debug_gui::run()?;
@@ -55,42 +78,27 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     println!("Hello, world!");
 
-    let mut plans = Vec::new();
-    for entry in glob(userdata_path.join("plans/**/plan.toml").to_str().unwrap()).unwrap() {
-        let path = entry.unwrap();
-        plans.push(path.parent().unwrap().to_path_buf());
-    }
-    let plans = plans
-        .iter()
-        .map(|x| (Plan::new(x), x))
-        .filter_map(|(x, y)| match x {
-            Ok(x) => Some(x),
-            Err(e) => {
-                eprintln!("Error loading plan: {}\n{:?}", y.display(), e);
-                None
-            }
-        })
-        .map(|(p, w)| {
-            if !w.is_empty() {
-                eprintln!("Warning in plan: {}", p.workdir.display());
-                for w in w {
-                    eprintln!("  {}", w);
-                }
-            }
-            p
-        })
-        .collect::<Vec<_>>();
+    let plans = load_plans(userdata_path);
 
     let mut server = ADBServer::new(config.adb.host);
     let device = server.try_connect_to_device(&config)?;
     let device = Arc::new(Mutex::new(device));
 
+    let history = RunHistory::load(&userdata_path.join("history.toml"))?;
+    let mut runner = Runner::new(&plans, device, ocr, history, Arc::downgrade(&debug_gui));
     loop {
-        for plan in &plans {
-            let device = device.clone();
-            let ocr = ocr.clone();
-            run_plan(device, ocr, plan, Arc::downgrade(&debug_gui))?;
+        let Some(next_wakeup) = runner.next_wakeup() else {
+            println!("No schedules left to run");
+            break;
+        };
+        let now = Local::now();
+        if now < next_wakeup {
+            let wait = (next_wakeup - now).to_std().unwrap_or_default();
+            sleep(wait.min(MAX_SCHEDULER_SLEEP));
+            continue;
         }
+
+        runner.run_due(now);
     }
 
     return Ok(());
@@ -127,53 +135,74 @@ fn main() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-fn run_plan(
-    device: Arc<Mutex<ADBServerDevice>>,
-    ocr: Arc<OcrEngine>,
-    plan: &Plan,
-    debug_gui: Weak<Mutex<DebugData>>,
-) -> Result<(), Box<dyn Error>> {
-    {
-        let mut dev = device.lock().unwrap();
-        dev.stop_app(&plan.package)?;
-        dev.start_app(&plan.package, &plan.activity)?;
+fn load_plans(userdata_path: &Path) -> Vec<Plan> {
+    let mut plans = Vec::new();
+    for entry in glob(userdata_path.join("plans/**/plan.toml").to_str().unwrap()).unwrap() {
+        let path = entry.unwrap();
+        plans.push(path.parent().unwrap().to_path_buf());
     }
-
-    let mut plan_engine = plan_engine::PlanEngine::new(plan, device, ocr, debug_gui);
-
-    // println!("{:?}", engine.get_state());
-
-    // lua.scope(|scope| {
-    //     globals.set(
-    //         "set_state",
-    //         scope.create_function_mut(|_, args: String| {
-    //             engine.set_state(args).unwrap();
-    //             Ok(())
-    //         })?,
-    //     )?;
-    //     lua.load(
-    //         r#"
-    //             set_state("title-1")
-    //         "#,
-    //     )
-    //     .exec()
-    // })?;
-
-    // println!("{:?}", engine.get_state());
-    for schedule in &plan.schedules {
-        match &schedule.action {
-            def::ScheduleActions::Routines(vec) => {
-                for routine in vec {
-                    let nav_target = plan.routine_location.get(routine).unwrap();
-                    plan_engine.navigate_to(nav_target)?;
-                    plan_engine.run_script(routine)?;
+    plans
+        .iter()
+        .map(|x| (Plan::new(x), x))
+        .filter_map(|(x, y)| match x {
+            Ok(x) => Some(x),
+            Err(e) => {
+                eprintln!("Error loading plan: {}\n{:?}", y.display(), e);
+                None
+            }
+        })
+        .map(|(p, w)| {
+            if !w.is_empty() {
+                eprintln!("Warning in plan: {}", p.workdir.display());
+                for w in w {
+                    eprintln!("  {}", w);
                 }
             }
-            def::ScheduleActions::Script(path) => {
-                println!("Running script {:?}", path);
+            p
+        })
+        .collect::<Vec<_>>()
+}
+
+/// prints the next `count` firings of every schedule, with the routines they
+/// run and the screens those routines start on
+fn print_preview(plans: &[Plan], count: usize) {
+    let now = Local::now();
+    for plan in plans {
+        println!("plan {}", plan.workdir.display());
+        for (i, schedule) in plan.schedules.iter().enumerate() {
+            println!(
+                "  schedule {} `{}`",
+                schedule.history_key(i),
+                schedule.on_calendar
+            );
+            let mut after = now;
+            for _ in 0..count {
+                let Some(firing) = plan.next_firing(&schedule.on_calendar, &after) else {
+                    break;
+                };
+                println!(
+                    "    {} (game day {})",
+                    firing.format("%Y-%m-%d %H:%M:%S"),
+                    plan.game_day(&firing)
+                );
+                after = firing;
+            }
+            match &schedule.action {
+                def::ScheduleActions::Routines(routines) => {
+                    for routine in plan.ordered_routines(routines) {
+                        let location = plan
+                            .routine_location
+                            .get(routine)
+                            .map(String::as_str)
+                            .unwrap_or("<no screen>");
+                        println!("    routine {} @ {}", routine.display(), location);
+                    }
+                }
+                def::ScheduleActions::Script(path) => {
+                    let location = schedule.start_at.as_deref().unwrap_or("<self-navigating>");
+                    println!("    script {} @ {}", path.display(), location);
+                }
             }
         }
     }
-    plan_engine.navigate_to("end")?;
-    Ok(())
 }
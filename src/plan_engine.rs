@@ -3,32 +3,43 @@ use std::{
     error::Error,
     fs,
     path::{Path, PathBuf},
-    sync::{Arc, Mutex, Weak},
+    rc::Rc,
+    sync::{Arc, LazyLock, Mutex, Weak},
     thread::sleep,
     time::Duration,
 };
 
 use adb_client::ADBServerDevice;
+use chrono::Local;
 use eframe::egui::debug_text::print;
-use errors::*;
-use image::{io::Reader as ImageReader, DynamicImage, GenericImage, GenericImageView, RgbaImage};
-use image_new::DynamicImage as DynamicImageNew;
-use mlua::{Function, Lua, Variadic};
+pub use errors::*;
+use image::{DynamicImage, GenericImage, GenericImageView, RgbaImage};
+use mlua::{FromLua, Function, IntoLua, Lua, Table, UserData, UserDataMethods, Value, Variadic};
 use ocrs::{ImageSource, OcrEngine};
 use pathfinding::prelude::{bfs, dfs};
+use regex::Regex;
 use template_matching::{find_extremes, match_template};
 
 use crate::{
     adb_device_ext::ADBDeviceSimpleCommand,
     debug_gui::{self, DebugData},
-    def::{Actions, Plan, Screen, ScreenGroup, ScreenIdent, ScreenTo, TextOperation},
-    image_stuff::{downgrade_image, RgbaImageNew},
+    def::{
+        default_color_key_tolerance, Actions, ColorKey, ColorMode, Comparison, FractionPart,
+        FuzzyMatch, OcrPreprocess, OcrThreshold, PixelColor, Plan, Popup, Screen, ScreenGroup,
+        ScreenIdent, ScreenTo, TextOperation,
+    },
+    image_stuff::{
+        color_planes, downgrade_image, preprocess_ocr, LumaF32Image, RgbaImageNew, Template,
+    },
+    viewport::ScaledDevice,
 };
 
 #[derive(Clone, Debug)]
 pub enum ScreenEngineAction {
     Identify(Vec<(String, Vec<ScreenIdent>)>),
     Navigate(String, ScreenTo),
+    /// close the popup covering the screen
+    Dismiss(String, ScreenTo),
     None,
 }
 
@@ -70,6 +81,24 @@ mod errors {
         }
     }
     impl Error for PathNotFoundError {}
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct InterruptedError;
+    impl std::fmt::Display for InterruptedError {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "Interrupted by a more urgent schedule")
+        }
+    }
+    impl Error for InterruptedError {}
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct ScreenNotIdentifiedError;
+    impl std::fmt::Display for ScreenNotIdentifiedError {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "No screen identified")
+        }
+    }
+    impl Error for ScreenNotIdentifiedError {}
 }
 
 #[derive(Clone, Debug, Hash, Eq, PartialEq)]
@@ -222,44 +251,71 @@ impl Default for ScreenState {
     }
 }
 
+/// failed identifications of the expected screen before every screen is tried
+const MAX_IDENT_MISSES: u32 = 3;
+/// failed identifications against every screen before navigation gives up
+const MAX_GLOBAL_IDENT_MISSES: u32 = 3;
+
 #[derive(Clone, Debug)]
 pub struct ScreenEngine {
     screens: HashMap<String, Screen>,
     screen_groups: HashMap<String, ScreenGroup>,
+    popups: HashMap<String, Popup>,
     state: ScreenState,
     navigate_plan: VecDeque<ScreenStatePathfinding>,
     idented: bool,
+    /// popup identified on top of the current screen
+    dismissing: Option<String>,
+    target: Option<String>,
+    /// identifications in a row that matched no screen
+    ident_misses: u32,
 }
 impl ScreenEngine {
     pub fn from_plan(plan: &Plan) -> Self {
         Self {
             screens: plan.screens.clone(),
             screen_groups: plan.screen_groups.clone(),
+            popups: plan.popups.clone(),
             state: Default::default(),
             navigate_plan: Default::default(),
             idented: plan.screens.get("start").unwrap().ident.is_empty(),
+            dismissing: None,
+            target: None,
+            ident_misses: 0,
         }
     }
 
     pub fn step(&mut self) -> Result<ScreenEngineAction, Box<dyn Error>> {
         loop {
+            if let Some(popup) = &self.dismissing {
+                let dismiss = self.popups.get(popup).unwrap().dismiss.clone();
+                return Ok(ScreenEngineAction::Dismiss(popup.clone(), dismiss));
+            }
+
             let front = self.navigate_plan.front();
             let Some(front) = front else {
+                // a popup may still cover the screen we arrived at
+                if !self.idented && !self.popup_idents(Some(&self.state.curr)).is_empty() {
+                    return Ok(ScreenEngineAction::Identify(self.current_screen_idents()));
+                }
                 return Ok(ScreenEngineAction::None);
             };
 
+            if self.ident_misses >= MAX_IDENT_MISSES {
+                let mut idents = self.popup_idents(None);
+                idents.extend(self.all_screen_idents());
+                return Ok(ScreenEngineAction::Identify(idents));
+            }
+
             if front.state.curr == self.state.curr {
                 self.step_navigate();
                 continue;
             }
 
             if !self.idented {
-                let ident = &self.screens.get(&self.state.curr).unwrap().ident;
-                if !ident.is_empty() {
-                    return Ok(ScreenEngineAction::Identify(vec![(
-                        self.state.curr.to_owned(),
-                        ident.clone(),
-                    )]));
+                let idents = self.current_screen_idents();
+                if !idents.is_empty() {
+                    return Ok(ScreenEngineAction::Identify(idents));
                 } else {
                     self.idented = true;
                 }
@@ -272,12 +328,87 @@ impl ScreenEngine {
         }
     }
 
-    pub fn mark_identified(&mut self, screen_name: &str) {
+    /// popups that may cover the current screen, then the screen itself.
+    /// Empty if there's nothing to check
+    fn current_screen_idents(&self) -> Vec<(String, Vec<ScreenIdent>)> {
+        let mut idents = self.popup_idents(Some(&self.state.curr));
+        let ident = &self.screens.get(&self.state.curr).unwrap().ident;
+        if idents.is_empty() && ident.is_empty() {
+            return Vec::new();
+        }
+        idents.push((self.state.curr.clone(), cheapest_first(ident)));
+        idents
+    }
+
+    /// popups that can show up on `screen`, or on any screen
+    fn popup_idents(&self, screen: Option<&str>) -> Vec<(String, Vec<ScreenIdent>)> {
+        let mut popups: Vec<_> = self
+            .popups
+            .iter()
+            .filter(|(_, popup)| {
+                screen.is_none_or(|screen| {
+                    popup.on.is_empty() || popup.on.iter().any(|on| on == screen)
+                })
+            })
+            .map(|(name, popup)| (name.clone(), cheapest_first(&popup.ident)))
+            .collect();
+        popups.sort_by(|a, b| a.0.cmp(&b.0));
+        popups
+    }
+
+    /// every screen that has idents, cheapest to identify first
+    fn all_screen_idents(&self) -> Vec<(String, Vec<ScreenIdent>)> {
+        let mut screens: Vec<_> = self
+            .screens
+            .iter()
+            .filter(|(_, screen)| !screen.ident.is_empty())
+            .map(|(name, screen)| (name.clone(), cheapest_first(&screen.ident)))
+            .collect();
+        screens.sort_by_key(|(name, ident)| {
+            (ident.iter().map(|id| id.cost()).sum::<u32>(), name.clone())
+        });
+        screens
+    }
+
+    /// a screen other than the expected one means we got lost, navigation
+    /// continues from there
+    pub fn mark_identified(&mut self, screen_name: &str) -> Result<(), Box<dyn Error>> {
+        if self.popups.contains_key(screen_name) {
+            self.dismissing = Some(screen_name.to_owned());
+            return Ok(());
+        }
+        self.ident_misses = 0;
         if screen_name != self.state.curr {
-            todo!("popups not supported yet");
+            println!(
+                "expected screen {}, but found {}",
+                self.state.curr, screen_name
+            );
+            self.state = ScreenState {
+                curr: screen_name.to_owned(),
+                back: Vec::new(),
+            };
+            if let Some(target) = self.target.clone() {
+                self.set_navigate_target(&target)?;
+            }
         }
 
         self.idented = true;
+        Ok(())
+    }
+
+    /// the screen under the popup still has to be identified
+    pub fn mark_dismissed(&mut self) {
+        self.dismissing = None;
+        self.idented = false;
+    }
+
+    pub fn mark_unidentified(&mut self) -> Result<(), Box<dyn Error>> {
+        self.ident_misses += 1;
+        if self.ident_misses >= MAX_IDENT_MISSES + MAX_GLOBAL_IDENT_MISSES {
+            self.ident_misses = 0;
+            return Err(ScreenNotIdentifiedError.into());
+        }
+        Ok(())
     }
 
     pub fn step_navigate(&mut self) {
@@ -320,6 +451,7 @@ impl ScreenEngine {
         let res = self.pathfind(screen_name)?;
 
         self.navigate_plan = VecDeque::from(res);
+        self.target = Some(screen_name.to_owned());
 
         Ok(())
     }
@@ -329,8 +461,10 @@ pub struct PlanEngine<'a> {
     plan: &'a Plan,
     ocr: Arc<OcrEngine>,
     device: Arc<Mutex<ADBServerDevice>>,
+    /// `device` in the plan's design resolution
+    screen: ScaledDevice,
     screen_engine: ScreenEngine,
-    lua: Lua,
+    lua: Rc<Lua>,
     debug_gui: Weak<Mutex<DebugData>>,
 }
 
@@ -343,21 +477,22 @@ impl<'a> PlanEngine<'a> {
     ) -> Self {
         let lua = Lua::new();
 
+        let screen = ScaledDevice::new(device.clone(), plan.design_resolution);
         let device_table = lua.create_table().unwrap();
-        let (d_1, d_2, d_3, d_4, d_5) = (
-            device.clone(),
-            device.clone(),
-            device.clone(),
-            device.clone(),
-            device.clone(),
+        let (d_1, d_2, d_3, d_4, d_5, d_6) = (
+            screen.clone(),
+            screen.clone(),
+            screen.clone(),
+            screen.clone(),
+            screen.clone(),
+            screen.clone(),
         );
         device_table
             .set(
                 "tap",
                 lua.create_function_mut(move |_, (x, y): (u32, u32)| {
                     println!("Tapping at {}, {}", x, y);
-                    let mut device = d_1.lock().unwrap();
-                    device.tap(x, y).unwrap();
+                    d_1.tap(x, y).unwrap();
                     Ok(())
                 })
                 .unwrap(),
@@ -368,8 +503,7 @@ impl<'a> PlanEngine<'a> {
                 "back",
                 lua.create_function_mut(move |_, ()| {
                     println!("Pressing back");
-                    let mut device = d_2.lock().unwrap();
-                    device.back().unwrap();
+                    d_2.back().unwrap();
                     Ok(())
                 })
                 .unwrap(),
@@ -378,8 +512,13 @@ impl<'a> PlanEngine<'a> {
         lua.globals().set("device", device_table).unwrap();
 
         let screen_table = lua.create_table().unwrap();
-        let (ocr_1, ocr_2, ocr_3) = (ocr.clone(), ocr.clone(), ocr.clone());
-        let (dbgui_1, dbgui_2, dbgui_3) = (debug_gui.clone(), debug_gui.clone(), debug_gui.clone());
+        let (ocr_1, ocr_2, ocr_3, ocr_4) = (ocr.clone(), ocr.clone(), ocr.clone(), ocr.clone());
+        let (dbgui_1, dbgui_2, dbgui_3, dbgui_4) = (
+            debug_gui.clone(),
+            debug_gui.clone(),
+            debug_gui.clone(),
+            debug_gui.clone(),
+        );
         let (pl_1, pl_2) = (plan.clone(), plan.clone());
         // screen_table
         //     .set(
@@ -395,17 +534,19 @@ impl<'a> PlanEngine<'a> {
             .set(
                 "image_match",
                 lua.create_function(move |_, (path, x, y): (String, u32, u32)| {
-                    let mut device = d_4.lock().unwrap();
                     let ocr = &ocr_2;
-                    let screenshot = device.framebuffer_inner().unwrap();
+                    let screenshot = d_4.screenshot().unwrap();
                     let matcher = ScreenIdent::ImageMatch {
                         image: PathBuf::from(path),
                         pos: (x, y),
+                        threshold: None,
+                        margin: None,
+                        color: ColorMode::Luma,
                     };
                     let res = matcher
                         .ident_screen(&pl_1, ocr, screenshot, dbgui_2.clone())
                         .unwrap();
-                    Ok(res)
+                    Ok((res.matched, res))
                 })
                 .unwrap(),
             )
@@ -415,17 +556,19 @@ impl<'a> PlanEngine<'a> {
                 "ref_match",
                 lua.create_function(
                     move |_, (path, x, y, width, height): (String, u32, u32, u32, u32)| {
-                        let mut device = d_5.lock().unwrap();
                         let ocr = &ocr_3;
-                        let screenshot = device.framebuffer_inner().unwrap();
+                        let screenshot = d_5.screenshot().unwrap();
                         let matcher = ScreenIdent::RefMatch {
                             reference: PathBuf::from(path),
                             rect: (x, y, width, height),
+                            threshold: None,
+                            margin: None,
+                            color: ColorMode::Luma,
                         };
                         let res = matcher
                             .ident_screen(&pl_2, ocr, screenshot, dbgui_3.clone())
                             .unwrap();
-                        Ok(res)
+                        Ok((res.matched, res))
                     },
                 )
                 .unwrap(),
@@ -434,17 +577,148 @@ impl<'a> PlanEngine<'a> {
         screen_table
             .set(
                 "ocr",
-                lua.create_function(move |_, (x, y, width, height): (u32, u32, u32, u32)| {
-                    let mut device = d_3.lock().unwrap();
+                lua.create_function(move |_, (x, y, width, height, preprocess): LuaOcrArgs| {
                     let ocr = &ocr_1;
-                    let screenshot = device.framebuffer_inner().unwrap();
-                    Ok(run_ocr(ocr, screenshot, (x, y, width, height), dbgui_1.clone()).unwrap())
+                    let screenshot = d_3.screenshot().unwrap();
+                    Ok(run_ocr(
+                        ocr,
+                        screenshot,
+                        (x, y, width, height),
+                        &preprocess.unwrap_or_default(),
+                        dbgui_1.clone(),
+                    )
+                    .unwrap())
+                })
+                .unwrap(),
+            )
+            .unwrap();
+        screen_table
+            .set(
+                "ocr_number",
+                lua.create_function(move |_, (x, y, width, height, preprocess): LuaOcrArgs| {
+                    let screenshot = d_6.screenshot().map_err(lua_error)?;
+                    let text = run_ocr(
+                        &ocr_4,
+                        screenshot,
+                        (x, y, width, height),
+                        &preprocess.unwrap_or_default(),
+                        dbgui_4.clone(),
+                    )
+                    .map_err(lua_error)?;
+                    Ok(match ParsedNumber::parse(&text) {
+                        Some(number) => (
+                            lua_number(number.value),
+                            number.total.map_or(Value::Nil, lua_number),
+                        ),
+                        None => (Value::Nil, Value::Nil),
+                    })
+                })
+                .unwrap(),
+            )
+            .unwrap();
+        let (d_7, pl_4) = (screen.clone(), plan.clone());
+        // x and y are the top left of the best match, like an ident result's
+        screen_table
+            .set(
+                "find_image",
+                lua.create_function(
+                    move |_, (path, rect, threshold): (String, Option<[u32; 4]>, Option<f32>)| {
+                        let screenshot = d_7.screenshot().map_err(lua_error)?;
+                        let template = pl_4
+                            .templates
+                            .get(&pl_4.workdir.join(path), None, ColorMode::Luma)
+                            .map_err(lua_error)?;
+                        let threshold = threshold.unwrap_or(pl_4.match_threshold);
+                        let area = rect.map(|[x, y, width, height]| (x, y, width, height));
+                        let best = template_scores(screenshot, &template, area, ColorMode::Luma)
+                            .map(|(scores, origin)| (find_extremes(&scores), origin))
+                            .filter(|(extremes, _)| extremes.min_value < threshold);
+                        let Some((extremes, origin)) = best else {
+                            return Ok((Value::Nil, Value::Nil, Value::Nil));
+                        };
+                        let (x, y) = extremes.min_value_location;
+                        let (x, y) = (origin.0 + x, origin.1 + y);
+                        Ok((
+                            Value::Integer(x.into()),
+                            Value::Integer(y.into()),
+                            Value::Number(extremes.min_value.into()),
+                        ))
+                    },
+                )
+                .unwrap(),
+            )
+            .unwrap();
+        let (d_8, pl_5) = (screen.clone(), plan.clone());
+        // top left corners too, like `find_image`
+        screen_table
+            .set(
+                "find_all_images",
+                lua.create_function(
+                    move |lua, (path, rect, threshold): (String, Option<[u32; 4]>, Option<f32>)| {
+                        let screenshot = d_8.screenshot().map_err(lua_error)?;
+                        let template = pl_5
+                            .templates
+                            .get(&pl_5.workdir.join(path), None, ColorMode::Luma)
+                            .map_err(lua_error)?;
+                        let threshold = threshold.unwrap_or(pl_5.match_threshold);
+                        let area = rect.map(|[x, y, width, height]| (x, y, width, height));
+                        let found = lua.create_table()?;
+                        let Some((scores, origin)) =
+                            template_scores(screenshot, &template, area, ColorMode::Luma)
+                        else {
+                            return Ok(found);
+                        };
+                        let matches = non_overlapping_matches(
+                            &scores,
+                            template.image.dimensions(),
+                            threshold,
+                        );
+                        for (location, score) in matches {
+                            let (x, y) = (origin.0 + location.0, origin.1 + location.1);
+                            let found_match = lua.create_table()?;
+                            found_match.set("x", x)?;
+                            found_match.set("y", y)?;
+                            found_match.set("score", score)?;
+                            found.push(found_match)?;
+                        }
+                        Ok(found)
+                    },
+                )
+                .unwrap(),
+            )
+            .unwrap();
+        let (d_9, pl_6, ocr_5, dbgui_5) = (
+            screen.clone(),
+            Rc::new(plan.clone()),
+            ocr.clone(),
+            debug_gui.clone(),
+        );
+        screen_table
+            .set(
+                "capture",
+                lua.create_function(move |_, ()| {
+                    Ok(Snapshot {
+                        screenshot: d_9.screenshot().map_err(lua_error)?,
+                        plan: pl_6.clone(),
+                        ocr: ocr_5.clone(),
+                        debug_gui: dbgui_5.clone(),
+                    })
                 })
                 .unwrap(),
             )
             .unwrap();
         lua.globals().set("screen", screen_table).unwrap();
 
+        let pl_3 = plan.clone();
+        lua.globals()
+            .set(
+                "game_day",
+                lua.create_function(move |_, ()| {
+                    Ok(pl_3.game_day(&Local::now()).format("%Y-%m-%d").to_string())
+                })
+                .unwrap(),
+            )
+            .unwrap();
         lua.globals()
             .set(
                 "sleep_after_interact",
@@ -470,25 +744,88 @@ impl<'a> PlanEngine<'a> {
             plan,
             ocr,
             device: device.clone(),
+            screen,
             screen_engine: ScreenEngine::from_plan(plan),
-            lua,
+            lua: Rc::new(lua),
             debug_gui,
         }
     }
 
+    /// (re)starts the app and forgets the current screen
+    pub fn restart_app(&mut self) -> Result<(), Box<dyn Error>> {
+        {
+            let mut device = self.device.lock().unwrap();
+            device.stop_app(&self.plan.package)?;
+            device.start_app(&self.plan.package, &self.plan.activity)?;
+        }
+        self.screen_engine = ScreenEngine::from_plan(self.plan);
+        Ok(())
+    }
+
+    /// runs the script's `run` function, scripts can move between screens
+    /// with `navigate_to(screen_name)`
     pub fn run_script(&mut self, routine_name: &Path) -> Result<(), Box<dyn Error>> {
-        self.lua.globals().raw_remove("run")?;
+        self.run_lua_script(routine_name, true)
+    }
+
+    /// runs `routine_name`'s `run`. Navigation and dismiss scripts run in the
+    /// middle of `navigate`, so they get a `navigate_to` that only errors: a
+    /// nested navigation would replace the plan the outer one is following
+    fn run_lua_script(
+        &mut self,
+        routine_name: &Path,
+        can_navigate: bool,
+    ) -> Result<(), Box<dyn Error>> {
         let script = fs::read_to_string(self.plan.workdir.join(routine_name))?;
-        self.lua
-            .load(&script)
-            .set_name(routine_name.to_string_lossy())
-            .exec()?;
-        let run_func = self.lua.globals().get::<_, Function>("run")?;
-        run_func.call(())?;
+        let lua = self.lua.clone();
+        // a navigation script may run inside another script's `navigate_to`
+        let outer_navigate_to = lua.globals().get::<_, Value>("navigate_to")?;
+        let res = lua.scope(|scope| {
+            let navigate_to = if can_navigate {
+                scope.create_function_mut(|_, screen_name: String| {
+                    self.navigate_to(&screen_name)
+                        .map_err(|e| mlua::Error::external(e.to_string()))
+                })?
+            } else {
+                scope.create_function(|_, screen_name: String| {
+                    Err::<(), _>(mlua::Error::external(format!(
+                        "can't navigate to {} from a navigation or dismiss script",
+                        screen_name
+                    )))
+                })?
+            };
+            lua.globals().set("navigate_to", navigate_to)?;
+            lua.globals().raw_remove("run")?;
+            lua.load(&script)
+                .set_name(routine_name.to_string_lossy())
+                .exec()?;
+            let run_func = lua.globals().get::<_, Function>("run")?;
+            run_func.call::<_, ()>(())
+        });
+        lua.globals().set("navigate_to", outer_navigate_to)?;
+        res?;
         Ok(())
     }
 
     pub fn navigate_to(&mut self, screen_name: &str) -> Result<(), Box<dyn Error>> {
+        self.navigate(screen_name, None)
+    }
+
+    /// like `navigate_to`, but stops with `InterruptedError` before any
+    /// navigation step once `interrupted` returns true
+    pub fn navigate_to_interruptible(
+        &mut self,
+        screen_name: &str,
+        interrupted: &dyn Fn() -> bool,
+    ) -> Result<(), Box<dyn Error>> {
+        self.navigate(screen_name, Some(interrupted))
+    }
+
+    fn navigate(
+        &mut self,
+        screen_name: &str,
+        interrupted: Option<&dyn Fn() -> bool>,
+    ) -> Result<(), Box<dyn Error>> {
         self.screen_engine.set_navigate_target(screen_name)?;
         'engine_loop: loop {
             'engine_step: {
@@ -496,26 +833,32 @@ impl<'a> PlanEngine<'a> {
                 println!("stepping");
                 match s {
                     ScreenEngineAction::Identify(screen_idents) => {
-                        let screenshot = self.device.lock().unwrap().framebuffer_inner()?;
+                        let screenshot = self.screen.screenshot()?;
                         let mut idented = false;
                         for (name, idents) in screen_idents {
                             let succ = idents
                                 .iter()
                                 .map(|id| {
-                                    id.ident_screen(
+                                    let res = id.ident_screen(
                                         self.plan,
                                         &self.ocr,
                                         screenshot.clone(),
                                         self.debug_gui.clone(),
-                                    )
+                                    );
+                                    if let (Ok(result), Some(debug_gui)) =
+                                        (&res, self.debug_gui.upgrade())
+                                    {
+                                        debug_gui.lock().unwrap().push_ident(&name, result);
+                                    }
+                                    res
                                 })
-                                .find(|res| res.is_err() || res.as_ref().is_ok_and(|v| !v));
+                                .find(|res| res.is_err() || res.as_ref().is_ok_and(|v| !v.matched));
                             match succ {
                                 Some(Err(err)) => return Err(err),
                                 Some(Ok(_)) => {}
                                 None => {
                                     println!("identified screen {}", name);
-                                    self.screen_engine.mark_identified(&name);
+                                    self.screen_engine.mark_identified(&name)?;
                                     idented = true;
                                     break;
                                 }
@@ -523,51 +866,30 @@ impl<'a> PlanEngine<'a> {
                         }
                         if !idented {
                             println!("No screen identified");
+                            self.screen_engine.mark_unidentified()?;
                         } else {
                             continue 'engine_loop;
                         }
                     }
                     ScreenEngineAction::Navigate(name, to) => {
+                        if interrupted.is_some_and(|f| f()) {
+                            return Err(InterruptedError.into());
+                        }
                         println!("Navigating to {}", name);
-                        match to {
-                            ScreenTo::Script(path) => {
-                                println!("Running script {:?}", path);
-
-                                match self.run_script(&path) {
-                                    Ok(()) => (),
-                                    Err(err) => {
-                                        if let Some(mlua::Error::FromLuaConversionError {
-                                            from: "nil",
-                                            ..
-                                        }) = err.downcast_ref::<mlua::Error>()
-                                        {
-                                            println!("⚠️ run function not found in script");
-                                            break 'engine_step;
-                                        }
-                                        return Err(err);
-                                    }
-                                };
-                            }
-                            ScreenTo::Actions(vec) => {
-                                let mut device = self.device.lock().unwrap();
-                                for act in vec {
-                                    match act {
-                                        Actions::Tap(xpos, ypos) => {
-                                            device.tap(xpos, ypos)?;
-                                        }
-                                        Actions::Back => {
-                                            device.back()?;
-                                        }
-                                        Actions::Swipe(x1, y1, x2, y2, dur_ms) => {
-                                            device.swipe(x1, y1, x2, y2, Some(dur_ms))?;
-                                        },
-                                        
-                                    }
-                                }
-                            }
+                        if !self.perform(to)? {
+                            break 'engine_step;
                         }
                         self.screen_engine.step_navigate();
                     }
+                    ScreenEngineAction::Dismiss(name, to) => {
+                        println!("Dismissing popup {}", name);
+                        let dismissed = self.perform(to)?;
+                        self.screen_engine.mark_dismissed();
+                        if !dismissed {
+                            // the popup would be dismissed again and again
+                            return Err(format!("can't dismiss popup {}", name).into());
+                        }
+                    }
                     ScreenEngineAction::None => {
                         println!("No more steps needed");
                         break 'engine_loop;
@@ -580,11 +902,165 @@ impl<'a> PlanEngine<'a> {
         Ok(())
     }
 
+    /// runs a navigation or dismiss action, `false` if its script has no `run`
+    fn perform(&mut self, to: ScreenTo) -> Result<bool, Box<dyn Error>> {
+        match to {
+            ScreenTo::Script(path) => {
+                println!("Running script {:?}", path);
+
+                match self.run_lua_script(&path, false) {
+                    Ok(()) => (),
+                    Err(err) => {
+                        if let Some(mlua::Error::FromLuaConversionError { from: "nil", .. }) =
+                            err.downcast_ref::<mlua::Error>()
+                        {
+                            println!("⚠️ run function not found in script");
+                            return Ok(false);
+                        }
+                        return Err(err);
+                    }
+                };
+            }
+            ScreenTo::Actions(vec) => {
+                for act in vec {
+                    match act {
+                        Actions::Tap(xpos, ypos) => {
+                            self.screen.tap(xpos, ypos)?;
+                        }
+                        Actions::Back => {
+                            self.screen.back()?;
+                        }
+                        Actions::Swipe(x1, y1, x2, y2, dur_ms) => {
+                            self.screen.swipe(x1, y1, x2, y2, Some(dur_ms))?;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(true)
+    }
+
     pub fn get_state(&self) -> &str {
         self.screen_engine.get_state()
     }
 }
 
+/// what an ident saw on the screen, kept so thresholds can be tuned
+#[derive(Clone, Debug, Default)]
+pub struct IdentResult {
+    pub matched: bool,
+    /// normalized difference of the best template match, or the largest
+    /// channel difference of a pixel check. Lower is closer
+    pub score: Option<f32>,
+    /// top left of the best template match, or the pixel furthest off
+    pub location: Option<(u32, u32)>,
+    pub threshold: Option<f32>,
+    /// what OCR read
+    pub text: Option<String>,
+    /// results of the idents inside `any`, `all` and `not`, in the order they ran
+    pub children: Vec<IdentResult>,
+}
+
+impl IdentResult {
+    fn matched(matched: bool) -> Self {
+        Self {
+            matched,
+            ..Default::default()
+        }
+    }
+}
+
+impl std::fmt::Display for IdentResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(if self.matched { "✅" } else { "❌" })?;
+        if let Some(score) = self.score {
+            write!(f, " score {:.4}", score)?;
+        }
+        if let Some(threshold) = self.threshold {
+            write!(f, " / {:.4}", threshold)?;
+        }
+        if let Some((x, y)) = self.location {
+            write!(f, " at [{}, {}]", x, y)?;
+        }
+        if let Some(text) = &self.text {
+            write!(f, " text {:?}", text)?;
+        }
+        if !self.children.is_empty() {
+            f.write_str(" (")?;
+            for (i, child) in self.children.iter().enumerate() {
+                if i > 0 {
+                    f.write_str(", ")?;
+                }
+                write!(f, "{}", child)?;
+            }
+            f.write_str(")")?;
+        }
+        Ok(())
+    }
+}
+
+/// the rect and optional preprocessing of an OCR call from Lua
+type LuaOcrArgs = (u32, u32, u32, u32, Option<OcrPreprocess>);
+
+/// `{ upscale = 2, threshold = "otsu" }`, like an ident's `preprocess`
+impl<'lua> FromLua<'lua> for OcrPreprocess {
+    fn from_lua(value: Value<'lua>, _: &'lua Lua) -> mlua::Result<Self> {
+        let Value::Table(table) = value else {
+            return Err(mlua::Error::FromLuaConversionError {
+                from: value.type_name(),
+                to: "OcrPreprocess",
+                message: Some("expected a table".to_string()),
+            });
+        };
+        let threshold = match table.get::<_, Value>("threshold")? {
+            Value::Nil => None,
+            Value::String(name) if name.to_str()? == "otsu" => Some(OcrThreshold::Otsu),
+            Value::Table(fixed) => Some(OcrThreshold::Fixed(fixed.get("fixed")?)),
+            other => {
+                return Err(mlua::Error::FromLuaConversionError {
+                    from: other.type_name(),
+                    to: "OcrThreshold",
+                    message: Some("expected \"otsu\" or { fixed = level }".to_string()),
+                })
+            }
+        };
+        let color_key = match table.get::<_, Option<Table>>("color_key")? {
+            Some(key) => Some(ColorKey {
+                rgb: key.get("rgb")?,
+                tolerance: key
+                    .get::<_, Option<u8>>("tolerance")?
+                    .unwrap_or_else(default_color_key_tolerance),
+            }),
+            None => None,
+        };
+        Ok(OcrPreprocess {
+            upscale: table.get("upscale")?,
+            color_key,
+            grayscale: table
+                .get::<_, Option<bool>>("grayscale")?
+                .unwrap_or_default(),
+            threshold,
+            invert: table.get::<_, Option<bool>>("invert")?.unwrap_or_default(),
+        })
+    }
+}
+
+impl<'lua> IntoLua<'lua> for IdentResult {
+    fn into_lua(self, lua: &'lua Lua) -> mlua::Result<Value<'lua>> {
+        let table = lua.create_table()?;
+        table.set("matched", self.matched)?;
+        table.set("score", self.score)?;
+        if let Some((x, y)) = self.location {
+            table.set("x", x)?;
+            table.set("y", y)?;
+        }
+        table.set("threshold", self.threshold)?;
+        table.set("text", self.text)?;
+        table.set("children", self.children)?;
+        Ok(Value::Table(table))
+    }
+}
+
 pub trait WorkingScreenIdent {
     fn ident_screen(
         &self,
@@ -592,7 +1068,17 @@ pub trait WorkingScreenIdent {
         ocr: &OcrEngine,
         screenshot: RgbaImageNew,
         debug_gui: Weak<Mutex<DebugData>>,
-    ) -> Result<bool, Box<dyn Error>>;
+    ) -> Result<IdentResult, Box<dyn Error>>;
+
+    /// rough relative cost of `ident_screen`
+    fn cost(&self) -> u32;
+}
+
+/// `idents` in the order they're cheapest to check
+fn cheapest_first(idents: &[ScreenIdent]) -> Vec<ScreenIdent> {
+    let mut idents = idents.to_vec();
+    idents.sort_by_key(|ident| ident.cost());
+    idents
 }
 
 impl WorkingScreenIdent for ScreenIdent {
@@ -602,117 +1088,316 @@ impl WorkingScreenIdent for ScreenIdent {
         ocr: &OcrEngine,
         screenshot: RgbaImageNew,
         debug_gui: Weak<Mutex<DebugData>>,
-    ) -> Result<bool, Box<dyn Error>> {
+    ) -> Result<IdentResult, Box<dyn Error>> {
         match self {
             ScreenIdent::RefMatch {
                 reference: ref_image_path,
                 rect,
+                threshold,
+                margin,
+                color,
             } => {
-                let ref_dyn_image = ImageReader::open(plan.workdir.join(ref_image_path))?
-                    .decode()?
-                    .crop(rect.0, rect.1, rect.2, rect.3);
-                let ref_image = ref_dyn_image.to_luma32f();
+                let threshold = threshold.unwrap_or(plan.match_threshold);
+                let margin = margin.unwrap_or(plan.match_margin);
+                let template =
+                    plan.templates
+                        .get(&plan.workdir.join(ref_image_path), Some(*rect), *color)?;
                 let screenshot = downgrade_image(screenshot);
+                let origin = (rect.0.saturating_sub(margin), rect.1.saturating_sub(margin));
                 let scr_dyn_image = DynamicImage::from(screenshot).crop(
-                    rect.0.saturating_sub(20),
-                    rect.1.saturating_sub(20),
-                    rect.2 + 40,
-                    rect.3 + 40,
+                    origin.0,
+                    origin.1,
+                    rect.2 + margin * 2,
+                    rect.3 + margin * 2,
                 );
-                let screenshot = scr_dyn_image.to_luma32f();
+                let screen_planes = color_planes(&scr_dyn_image, *color);
                 if let Some(debug_gui) = debug_gui.upgrade() {
                     let mut debug_gui = debug_gui.lock().unwrap();
                     debug_gui.push_text("Ref match:");
                     debug_gui.push_image(scr_dyn_image);
-                    debug_gui.push_image(ref_dyn_image);
-                }
-                let m = match_template(
-                    &screenshot,
-                    &ref_image,
-                    template_matching::MatchTemplateMethod::SumOfSquaredDifferences,
-                );
-                let extremes = get_normalized_extremes(&find_extremes(&m), ref_image.dimensions());
-                if let Some(debug_gui) = debug_gui.upgrade() {
-                    let mut debug_gui = debug_gui.lock().unwrap();
-                    debug_gui.push_text(&format!("{:?}", extremes));
+                    debug_gui.push_image(template.image.clone());
                 }
-                dbg!(&extremes);
+                let m = match_color_template(&screen_planes, &template.planes);
+                let extremes =
+                    get_normalized_extremes(&find_extremes(&m), template.image.dimensions());
 
-                Ok(extremes.min_value < 0.04)
+                Ok(template_result(&extremes, origin, threshold))
             }
             ScreenIdent::ImageMatch {
                 image: image_path,
                 pos,
+                threshold,
+                margin,
+                color,
             } => {
-                let ref_dyn_image = ImageReader::open(plan.workdir.join(image_path))?.decode()?;
-                let ref_image = ref_dyn_image.to_luma32f();
+                let threshold = threshold.unwrap_or(plan.match_threshold);
+                let margin = margin.unwrap_or(plan.match_margin);
+                let template = plan
+                    .templates
+                    .get(&plan.workdir.join(image_path), None, *color)?;
                 let screenshot = downgrade_image(screenshot);
+                let origin = (pos.0.saturating_sub(margin), pos.1.saturating_sub(margin));
                 let scr_dyn_image = DynamicImage::from(screenshot).crop(
-                    pos.0.saturating_sub(20),
-                    pos.1.saturating_sub(20),
-                    ref_image.width() + 40,
-                    ref_image.height() + 40,
+                    origin.0,
+                    origin.1,
+                    template.image.width() + margin * 2,
+                    template.image.height() + margin * 2,
                 );
-                let screenshot = scr_dyn_image.to_luma32f();
+                let screen_planes = color_planes(&scr_dyn_image, *color);
                 if let Some(debug_gui) = debug_gui.upgrade() {
                     let mut debug_gui = debug_gui.lock().unwrap();
                     debug_gui.push_text("Image match:");
                     debug_gui.push_image(scr_dyn_image);
-                    debug_gui.push_image(ref_dyn_image);
+                    debug_gui.push_image(template.image.clone());
                 }
-                let m = match_template(
-                    &screenshot,
-                    &ref_image,
-                    template_matching::MatchTemplateMethod::SumOfSquaredDifferences,
-                );
-                let extremes = get_normalized_extremes(&find_extremes(&m), ref_image.dimensions());
-                if let Some(debug_gui) = debug_gui.upgrade() {
-                    let mut debug_gui = debug_gui.lock().unwrap();
-                    debug_gui.push_text(&format!("{:?}", extremes));
-                }
-                dbg!(&extremes);
+                let m = match_color_template(&screen_planes, &template.planes);
+                let extremes =
+                    get_normalized_extremes(&find_extremes(&m), template.image.dimensions());
 
-                Ok(extremes.min_value < 0.04)
+                Ok(template_result(&extremes, origin, threshold))
             }
             ScreenIdent::Ocr {
                 ocr: ocr_target,
                 operation,
                 rect,
+                preprocess,
             } => {
-                let text = run_ocr(ocr, screenshot, *rect, debug_gui)?;
-                Ok(operation.run(&text, ocr_target))
+                let text = run_ocr(ocr, screenshot, *rect, preprocess, debug_gui)?;
+                Ok(IdentResult {
+                    matched: operation.run(&text, ocr_target, plan)?,
+                    text: Some(text),
+                    ..Default::default()
+                })
+            }
+            ScreenIdent::OcrNumber {
+                comparison,
+                value,
+                rect,
+                part,
+                preprocess,
+            } => {
+                let text = run_ocr(ocr, screenshot, *rect, preprocess, debug_gui)?;
+                let read = ParsedNumber::parse(&text).and_then(|number| match part {
+                    FractionPart::Numerator => Some(number.value),
+                    FractionPart::Denominator => number.total,
+                });
+                Ok(IdentResult {
+                    matched: read.is_some_and(|read| comparison.compare(read, *value)),
+                    text: Some(text),
+                    ..Default::default()
+                })
+            }
+            ScreenIdent::Pixel { pixel, tolerance } => {
+                let mut result = IdentResult {
+                    matched: true,
+                    threshold: Some((*tolerance).into()),
+                    ..Default::default()
+                };
+                for PixelColor { pos, rgb } in pixel {
+                    let Some(actual) = screenshot.get_pixel_checked(pos.0, pos.1) else {
+                        result.matched = false;
+                        continue;
+                    };
+                    if let Some(debug_gui) = debug_gui.upgrade() {
+                        let mut debug_gui = debug_gui.lock().unwrap();
+                        debug_gui.push_text(&format!(
+                            "Pixel {:?}: {:?}, expected {:?}",
+                            pos, actual.0, rgb
+                        ));
+                    }
+                    let diff = rgb
+                        .iter()
+                        .zip(actual.0)
+                        .map(|(expected, actual)| expected.abs_diff(actual))
+                        .max()
+                        .unwrap_or(0);
+                    if diff > *tolerance {
+                        result.matched = false;
+                    }
+                    if result.score.is_none_or(|score| f32::from(diff) > score) {
+                        result.score = Some(diff.into());
+                        result.location = Some(*pos);
+                    }
+                }
+                Ok(result)
+            }
+            ScreenIdent::Any { any } => {
+                let mut result = IdentResult::matched(false);
+                for ident in any {
+                    let child =
+                        ident.ident_screen(plan, ocr, screenshot.clone(), debug_gui.clone())?;
+                    result.matched = child.matched;
+                    result.children.push(child);
+                    if result.matched {
+                        break;
+                    }
+                }
+                Ok(result)
+            }
+            ScreenIdent::All { all } => {
+                let mut result = IdentResult::matched(true);
+                for ident in all {
+                    let child =
+                        ident.ident_screen(plan, ocr, screenshot.clone(), debug_gui.clone())?;
+                    result.matched = child.matched;
+                    result.children.push(child);
+                    if !result.matched {
+                        break;
+                    }
+                }
+                Ok(result)
+            }
+            ScreenIdent::Not { not } => {
+                let child = not.ident_screen(plan, ocr, screenshot, debug_gui)?;
+                Ok(IdentResult {
+                    matched: !child.matched,
+                    children: vec![child],
+                    ..Default::default()
+                })
             }
         }
     }
+
+    fn cost(&self) -> u32 {
+        match self {
+            ScreenIdent::Pixel { .. } => 1,
+            ScreenIdent::RefMatch { .. } | ScreenIdent::ImageMatch { .. } => 10,
+            ScreenIdent::Ocr { .. } | ScreenIdent::OcrNumber { .. } => 100,
+            ScreenIdent::Any { any: idents } | ScreenIdent::All { all: idents } => {
+                idents.iter().map(|ident| ident.cost()).sum()
+            }
+            ScreenIdent::Not { not } => not.cost(),
+        }
+    }
+}
+
+trait WorkingComparison {
+    fn compare(&self, left: f64, right: f64) -> bool;
+}
+
+impl WorkingComparison for Comparison {
+    fn compare(&self, left: f64, right: f64) -> bool {
+        match self {
+            Comparison::Eq => left == right,
+            Comparison::Ne => left != right,
+            Comparison::Lt => left < right,
+            Comparison::Le => left <= right,
+            Comparison::Gt => left > right,
+            Comparison::Ge => left >= right,
+        }
+    }
+}
+
+const NUMBER_PATTERN: &str = r"(\d[\d,]*(?:\.\d+)?)";
+static NUMBER: LazyLock<Regex> = LazyLock::new(|| Regex::new(NUMBER_PATTERN).unwrap());
+static FRACTION: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(&format!(r"{}\s*/\s*{}", NUMBER_PATTERN, NUMBER_PATTERN)).unwrap());
+
+/// a number or `a/b` fraction read by OCR, like `1,200` or `35/120`
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct ParsedNumber {
+    value: f64,
+    /// the denominator of a fraction
+    total: Option<f64>,
+}
+
+impl ParsedNumber {
+    /// the first fraction in `text`, or else the first number
+    fn parse(text: &str) -> Option<Self> {
+        let parse = |s: &str| s.replace(',', "").parse::<f64>().ok();
+        if let Some(captures) = FRACTION.captures(text) {
+            return Some(Self {
+                value: parse(&captures[1])?,
+                total: Some(parse(&captures[2])?),
+            });
+        }
+        let captures = NUMBER.captures(text)?;
+        Some(Self {
+            value: parse(&captures[1])?,
+            total: None,
+        })
+    }
+}
+
+/// integral values become Lua integers so scripts see `40` instead of `40.0`
+fn lua_number(value: f64) -> Value<'static> {
+    if value.fract() == 0.0 && value.abs() < i64::MAX as f64 {
+        Value::Integer(value as i64)
+    } else {
+        Value::Number(value)
+    }
 }
 
 trait WorkingTextOp {
-    fn run(&self, text: &str, target: &str) -> bool;
+    fn run(&self, text: &str, target: &str, plan: &Plan) -> Result<bool, Box<dyn Error>>;
 }
 
 impl WorkingTextOp for TextOperation {
-    fn run(&self, text: &str, target: &str) -> bool {
-        match self {
+    fn run(&self, text: &str, target: &str, plan: &Plan) -> Result<bool, Box<dyn Error>> {
+        Ok(match self {
             TextOperation::Exact => text == target,
             TextOperation::Contains => text.contains(target),
             TextOperation::StartsWith => text.starts_with(target),
             TextOperation::EndsWith => text.ends_with(target),
+            // idents from the plan are compiled when it loads
+            TextOperation::Regex => match plan.regexes.get(target) {
+                Some(regex) => regex.is_match(text),
+                None => Regex::new(target)?.is_match(text),
+            },
+            TextOperation::Fuzzy(FuzzyMatch {
+                max_distance,
+                min_ratio,
+            }) => {
+                let distance = edit_distance(text, target);
+                let len = text.chars().count().max(target.chars().count());
+                let ratio = if len == 0 {
+                    1.0
+                } else {
+                    1.0 - distance as f32 / len as f32
+                };
+                match (max_distance, min_ratio) {
+                    (None, None) => distance <= 1,
+                    _ => {
+                        max_distance.is_none_or(|max| distance <= max)
+                            && min_ratio.is_none_or(|min| ratio >= min)
+                    }
+                }
+            }
+        })
+    }
+}
+
+/// Levenshtein distance in characters
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    let mut curr = vec![0; b.len() + 1];
+    for (i, ca) in a.chars().enumerate() {
+        curr[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = prev[j] + usize::from(ca != *cb);
+            curr[j + 1] = substitution.min(prev[j + 1] + 1).min(curr[j] + 1);
         }
+        std::mem::swap(&mut prev, &mut curr);
     }
+    prev[b.len()]
 }
 
 fn run_ocr(
     ocr: &OcrEngine,
     screenshot: RgbaImageNew,
     rect: (u32, u32, u32, u32),
+    preprocess: &OcrPreprocess,
     debug_gui: Weak<Mutex<DebugData>>,
 ) -> Result<String, Box<dyn Error>> {
-    let dyn_image = DynamicImageNew::from(screenshot).crop(rect.0, rect.1, rect.2, rect.3);
+    let area = image_new::imageops::crop_imm(&screenshot, rect.0, rect.1, rect.2, rect.3);
+    let dyn_image = DynamicImage::from(downgrade_image(area.to_image()));
+    let dyn_image = preprocess_ocr(dyn_image, preprocess);
     let screenshot = dyn_image.to_rgb8();
     if let Some(debug_gui) = debug_gui.upgrade() {
         let mut debug_gui = debug_gui.lock().unwrap();
         debug_gui.push_text("OCR:");
-        debug_gui.push_image_new(dyn_image);
+        debug_gui.push_image(dyn_image);
     }
     let screenshot = ImageSource::from_bytes(screenshot.as_raw(), screenshot.dimensions())?;
     let screenshot = ocr.prepare_input(screenshot)?;
@@ -726,6 +1411,186 @@ fn run_ocr(
     Ok(text.to_string())
 }
 
+/// normalized difference of `template` at every position in `area` of
+/// `screenshot`, and the area's top left corner. `None` if the template doesn't
+/// fit in the area
+fn template_scores(
+    screenshot: RgbaImageNew,
+    template: &Template,
+    area: Option<(u32, u32, u32, u32)>,
+    mode: ColorMode,
+) -> Option<(template_matching::Image<'static>, (u32, u32))> {
+    let (width, height) = screenshot.dimensions();
+    let (x, y, area_width, area_height) = area.unwrap_or((0, 0, width, height));
+    let (x, y) = (x.min(width), y.min(height));
+    let (area_width, area_height) = (area_width.min(width - x), area_height.min(height - y));
+    let (template_width, template_height) = template.image.dimensions();
+    if area_width < template_width || area_height < template_height {
+        return None;
+    }
+    let area_image =
+        DynamicImage::from(downgrade_image(screenshot)).crop_imm(x, y, area_width, area_height);
+    let mut scores = match_color_template(&color_planes(&area_image, mode), &template.planes);
+    let size = (template_width * template_height) as f32;
+    for score in scores.data.to_mut().iter_mut() {
+        *score /= size;
+    }
+    Some((scores, (x, y)))
+}
+
+/// positions scoring under `threshold`, best first, without any that overlap a
+/// better one
+fn non_overlapping_matches(
+    scores: &template_matching::Image,
+    template_size: (u32, u32),
+    threshold: f32,
+) -> Vec<((u32, u32), f32)> {
+    let mut candidates: Vec<_> = scores
+        .data
+        .iter()
+        .enumerate()
+        .filter(|(_, score)| **score < threshold)
+        .map(|(i, score)| {
+            let i = i as u32;
+            ((i % scores.width, i / scores.width), *score)
+        })
+        .collect();
+    candidates.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+    let mut matches: Vec<((u32, u32), f32)> = Vec::new();
+    for (location, score) in candidates {
+        let overlaps = matches.iter().any(|(kept, _)| {
+            location.0.abs_diff(kept.0) < template_size.0
+                && location.1.abs_diff(kept.1) < template_size.1
+        });
+        if !overlaps {
+            matches.push((location, score));
+        }
+    }
+    matches
+}
+
+/// a single screenshot from `screen.capture`, so a script can check many
+/// things on the same frame without taking a screenshot for each
+struct Snapshot {
+    screenshot: RgbaImageNew,
+    plan: Rc<Plan>,
+    ocr: Arc<OcrEngine>,
+    debug_gui: Weak<Mutex<DebugData>>,
+}
+
+impl Snapshot {
+    fn ident(&self, ident: ScreenIdent) -> mlua::Result<(bool, IdentResult)> {
+        let result = ident
+            .ident_screen(
+                &self.plan,
+                &self.ocr,
+                self.screenshot.clone(),
+                self.debug_gui.clone(),
+            )
+            .map_err(lua_error)?;
+        Ok((result.matched, result))
+    }
+}
+
+impl UserData for Snapshot {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method(
+            "image_match",
+            |_, this, (path, x, y): (String, u32, u32)| {
+                this.ident(ScreenIdent::ImageMatch {
+                    image: PathBuf::from(path),
+                    pos: (x, y),
+                    threshold: None,
+                    margin: None,
+                    color: ColorMode::Luma,
+                })
+            },
+        );
+        methods.add_method(
+            "ref_match",
+            |_, this, (path, x, y, width, height): (String, u32, u32, u32, u32)| {
+                this.ident(ScreenIdent::RefMatch {
+                    reference: PathBuf::from(path),
+                    rect: (x, y, width, height),
+                    threshold: None,
+                    margin: None,
+                    color: ColorMode::Luma,
+                })
+            },
+        );
+        methods.add_method(
+            "ocr",
+            |_, this, (x, y, width, height, preprocess): LuaOcrArgs| {
+                run_ocr(
+                    &this.ocr,
+                    this.screenshot.clone(),
+                    (x, y, width, height),
+                    &preprocess.unwrap_or_default(),
+                    this.debug_gui.clone(),
+                )
+                .map_err(lua_error)
+            },
+        );
+        methods.add_method("pixel", |_, this, (x, y): (u32, u32)| {
+            Ok(match this.screenshot.get_pixel_checked(x, y) {
+                Some(pixel) => (Some(pixel[0]), Some(pixel[1]), Some(pixel[2])),
+                None => (None, None, None),
+            })
+        });
+    }
+}
+
+fn lua_error(err: Box<dyn Error>) -> mlua::Error {
+    mlua::Error::external(err.to_string())
+}
+
+/// squared differences summed over the planes and averaged, so thresholds
+/// mean about the same in every color mode
+fn match_color_template(
+    screenshot: &[LumaF32Image],
+    template: &[LumaF32Image],
+) -> template_matching::Image<'static> {
+    let mut planes = screenshot
+        .iter()
+        .zip(template)
+        .map(|(screenshot, template)| {
+            match_template(
+                screenshot,
+                template,
+                template_matching::MatchTemplateMethod::SumOfSquaredDifferences,
+            )
+        });
+    let mut sum = planes.next().expect("images to have at least one plane");
+    let mut count = 1.0;
+    for plane in planes {
+        for (sum, value) in sum.data.to_mut().iter_mut().zip(plane.data.iter()) {
+            *sum += value;
+        }
+        count += 1.0;
+    }
+    if count > 1.0 {
+        sum.data.to_mut().iter_mut().for_each(|sum| *sum /= count);
+    }
+    sum
+}
+
+/// result of a template matched in an area starting at `origin`
+fn template_result(
+    extremes: &template_matching::Extremes,
+    origin: (u32, u32),
+    threshold: f32,
+) -> IdentResult {
+    let (x, y) = extremes.min_value_location;
+    IdentResult {
+        matched: extremes.min_value < threshold,
+        score: Some(extremes.min_value),
+        location: Some((origin.0 + x, origin.1 + y)),
+        threshold: Some(threshold),
+        ..Default::default()
+    }
+}
+
 fn get_normalized_extremes(
     extremes: &template_matching::Extremes,
     template_size: (u32, u32),
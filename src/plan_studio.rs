@@ -6,7 +6,7 @@ use eframe::egui::{self, Pos2, Sense};
 use image_new::ImageReader;
 use itertools::Itertools;
 use ocrs::{ImageSource, OcrEngine, OcrEngineParams};
-use plan_engine::WorkingScreenIdent;
+use plan_engine::{IdentResult, WorkingScreenIdent};
 use rten::Model;
 use std::{
     error::Error,
@@ -16,11 +16,14 @@ use std::{
     thread,
     time::Duration,
 };
+use viewport::Viewport;
 mod adb_device_ext;
+mod calendar;
 mod debug_gui;
 mod def;
 mod image_stuff;
 mod plan_engine;
+mod viewport;
 
 fn main() -> Result<(), Box<dyn Error>> {
     let userdata_path = Path::new("./userdata"); // TODO
@@ -76,7 +79,7 @@ struct MyApp {
     selected_screen: String,
     manual_ident: String,
     counter: usize,
-    ident_result: Arc<Mutex<Result<Vec<bool>, String>>>,
+    ident_result: Arc<Mutex<Result<Vec<IdentResult>, String>>>,
     current_image: Arc<Mutex<Option<PathBuf>>>,
     screenshot_loading: Arc<AtomicBool>,
     ident_loading: Arc<AtomicBool>,
@@ -91,11 +94,12 @@ impl MyApp {
         let ident_loading = Arc::new(AtomicBool::new(false));
         let plan = Arc::new(Mutex::new(plan));
         let ocr = Arc::new(ocr);
-        let ident_result: Arc<Mutex<Result<Vec<bool>, String>>> =
+        let ident_result: Arc<Mutex<Result<Vec<IdentResult>, String>>> =
             Arc::new(Mutex::new(Ok(Vec::new())));
 
         let current_image_1 = current_image.clone();
         let screenshot_loading_1 = screenshot_loading.clone();
+        let plan_0 = plan.clone();
         let (scr_tx, scr_rx) = mpsc::channel::<()>();
         thread::spawn(move || {
             let mut counter = 0;
@@ -115,6 +119,11 @@ impl MyApp {
                 fs::create_dir_all(path.parent().unwrap()).unwrap();
 
                 let image = device.framebuffer_inner().unwrap();
+                // captures are in design coordinates, like everything in the plan
+                let image = match plan_0.lock().unwrap().design_resolution {
+                    Some(design) => Viewport::new(design, image.dimensions()).design_image(&image),
+                    None => image,
+                };
                 image.save(&path).unwrap();
                 *current_image_1.lock().unwrap() = Some(path);
                 screenshot_loading_1.store(false, std::sync::atomic::Ordering::Relaxed);
@@ -189,8 +198,7 @@ impl MyApp {
                     *idrs_1.lock().unwrap() = Err(format!("{:?}", e.as_ref().unwrap_err()));
                     continue;
                 } else {
-                    *idrs_1.lock().unwrap() =
-                        Ok(reses.iter().map(|v| *v.as_ref().unwrap()).collect());
+                    *idrs_1.lock().unwrap() = Ok(reses.into_iter().map(Result::unwrap).collect());
                 }
 
                 ident_loading_1.store(false, std::sync::atomic::Ordering::Relaxed);
@@ -330,7 +338,7 @@ impl eframe::App for MyApp {
                                 Ok(res) => {
                                     ui.label("Result:");
                                     for v in res.iter() {
-                                        ui.label(if *v { "✅" } else { "❌" });
+                                        ui.label(v.to_string());
                                     }
                                 }
                                 Err(e) => {
@@ -0,0 +1,429 @@
+use std::{
+    cmp::Reverse,
+    error::Error,
+    path::Path,
+    sync::{Arc, Mutex, Weak},
+    thread::sleep,
+    time::Duration,
+};
+
+use adb_client::ADBServerDevice;
+use chrono::{DateTime, Local, TimeDelta};
+use ocrs::OcrEngine;
+
+use crate::{
+    adb_device_ext::ADBDeviceStatus,
+    debug_gui::DebugData,
+    def::{CatchUp, DeviceRequirements, Plan, Schedule, ScheduleActions},
+    history::{RunHistory, RunOutcome},
+    plan_engine::{InterruptedError, PlanEngine},
+    scheduler::{self, DueSchedule, Scheduler},
+};
+
+/// wait before trying a deferred schedule again
+const DEFER_DELAY: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Clone, Copy, Debug)]
+struct PendingRun {
+    due: DueSchedule,
+    /// routine to continue from after an interruption
+    next_step: usize,
+    started: bool,
+    /// deferred runs wait until then
+    ready_at: DateTime<Local>,
+}
+
+impl PendingRun {
+    fn is_ready(&self, now: DateTime<Local>) -> bool {
+        self.ready_at <= now
+    }
+}
+
+enum ScheduleProgress {
+    Done(RunOutcome),
+    /// stopped before the routine at this index
+    Interrupted(usize),
+}
+
+/// runs due schedules, most urgent first, and lets urgent schedules
+/// interrupt `interruptible` ones between routines and navigation steps
+pub struct Runner<'a> {
+    plans: &'a [Plan],
+    device: Arc<Mutex<ADBServerDevice>>,
+    ocr: Arc<OcrEngine>,
+    debug_gui: Weak<Mutex<DebugData>>,
+    history: RunHistory,
+    scheduler: Scheduler,
+    pending: Vec<PendingRun>,
+}
+
+impl<'a> Runner<'a> {
+    pub fn new(
+        plans: &'a [Plan],
+        device: Arc<Mutex<ADBServerDevice>>,
+        ocr: Arc<OcrEngine>,
+        history: RunHistory,
+        debug_gui: Weak<Mutex<DebugData>>,
+    ) -> Self {
+        let scheduler = Scheduler::new(plans, &history, Local::now());
+        Self {
+            plans,
+            device,
+            ocr,
+            debug_gui,
+            history,
+            scheduler,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn next_wakeup(&self) -> Option<DateTime<Local>> {
+        self.pending
+            .iter()
+            .map(|run| run.ready_at)
+            .chain(self.scheduler.next_firing())
+            .min()
+    }
+
+    fn schedule(&self, due: &DueSchedule) -> &'a Schedule {
+        schedule_of(self.plans, due)
+    }
+
+    fn urgency(&self, run: &PendingRun) -> impl Ord {
+        (
+            Reverse(self.schedule(&run.due).priority),
+            run.due.firing,
+            run.due.id,
+        )
+    }
+
+    fn collect_due(&mut self, now: DateTime<Local>) {
+        let due = self.scheduler.take_due(self.plans, now);
+        for due in due {
+            // a fresh firing replaces a deferred one unless every firing counts
+            if self.schedule(&due).catch_up != CatchUp::All {
+                self.pending
+                    .retain(|run| run.started || run.due.id != due.id);
+            }
+            self.pending.push(PendingRun {
+                due,
+                next_step: 0,
+                started: false,
+                ready_at: due.firing,
+            });
+        }
+    }
+
+    /// retries `run` after `DEFER_DELAY`, or drops it if that would be too late
+    fn defer(&mut self, mut run: PendingRun, reason: &str) {
+        let schedule = self.schedule(&run.due);
+        let retry_at = Local::now() + TimeDelta::from_std(DEFER_DELAY).unwrap();
+        let too_late = scheduler::max_lateness(schedule)
+            .is_some_and(|max| (retry_at - run.due.firing).to_std().unwrap_or_default() > max);
+        if too_late {
+            println!(
+                "Dropping schedule `{}`, {} until it was too late",
+                schedule.on_calendar, reason
+            );
+        } else {
+            println!("Deferring schedule `{}`, {}", schedule.on_calendar, reason);
+            run.ready_at = retry_at;
+            self.pending.push(run);
+        }
+    }
+
+    /// defers runs whose device requirements are unmet and returns the rest
+    fn check_requirements(&mut self, batch: Vec<PendingRun>) -> Vec<PendingRun> {
+        let mut ready = Vec::new();
+        for run in batch {
+            let schedule = self.schedule(&run.due);
+            let unmet = {
+                let mut device = self.device.lock().unwrap();
+                unmet_requirement(&mut device, &schedule.device)
+                    .unwrap_or_else(|err| Some(format!("device unreachable: {}", err)))
+            };
+            match unmet {
+                Some(reason) => self.defer(run, &reason),
+                None => ready.push(run),
+            }
+        }
+        ready
+    }
+
+    /// runs everything due at `now`, along with anything that becomes due
+    /// meanwhile
+    pub fn run_due(&mut self, now: DateTime<Local>) {
+        self.collect_due(now);
+        loop {
+            let now = Local::now();
+            let Some(first) = self
+                .pending
+                .iter()
+                .filter(|run| run.is_ready(now))
+                .min_by_key(|run| self.urgency(run))
+            else {
+                break;
+            };
+            let plan_idx = first.due.id.plan;
+            let (batch, rest) = self
+                .pending
+                .drain(..)
+                .partition::<Vec<_>, _>(|run| run.due.id.plan == plan_idx && run.is_ready(now));
+            self.pending = rest;
+
+            let mut batch = self.check_requirements(batch);
+            batch.sort_by_key(|run| self.urgency(run));
+            if !batch.is_empty() {
+                if let Err(err) = self.run_plan(plan_idx, batch) {
+                    eprintln!(
+                        "plan {} failed: {}",
+                        self.plans[plan_idx].workdir.display(),
+                        err
+                    );
+                }
+            }
+            self.collect_due(Local::now());
+        }
+    }
+
+    fn run_plan(&mut self, plan_idx: usize, batch: Vec<PendingRun>) -> Result<(), Box<dyn Error>> {
+        let plans = self.plans;
+        let plan = &plans[plan_idx];
+        let mut plan_engine = PlanEngine::new(
+            plan,
+            self.device.clone(),
+            self.ocr.clone(),
+            self.debug_gui.clone(),
+        );
+        if let Err(err) = plan_engine.restart_app() {
+            let reason = format!("app failed to start: {}", err);
+            for run in batch {
+                self.defer(run, &reason);
+            }
+            return Ok(());
+        }
+
+        let mut batch = batch.into_iter();
+        while let Some(mut run) = batch.next() {
+            let schedule = self.schedule(&run.due);
+            let history_key = schedule.history_key(run.due.id.schedule);
+            if run.started {
+                println!("Resuming schedule `{}`", schedule.on_calendar);
+            } else {
+                println!("Running schedule `{}`", schedule.on_calendar);
+                self.history
+                    .record_start(plan, &history_key, run.due.firing)?;
+                run.started = true;
+            }
+
+            let (pending, scheduler, device) = (&self.pending, &self.scheduler, &self.device);
+            let preempted = || {
+                schedule.interruptible
+                    && preempts(plans, pending, scheduler, device, schedule.priority)
+            };
+            let progress = run_schedule(
+                &mut plan_engine,
+                &mut self.history,
+                plan,
+                schedule,
+                run.next_step,
+                &preempted,
+            )?;
+            match progress {
+                ScheduleProgress::Done(outcome) => {
+                    self.history.record_finish(plan, &history_key, outcome)?;
+                }
+                ScheduleProgress::Interrupted(step) => {
+                    println!("Interrupted schedule `{}`", schedule.on_calendar);
+                    run.next_step = step;
+                    self.pending.push(run);
+                    self.pending.extend(batch);
+                    return Ok(());
+                }
+            }
+        }
+        plan_engine.navigate_to("end")?;
+        Ok(())
+    }
+}
+
+fn schedule_of<'a>(plans: &'a [Plan], due: &DueSchedule) -> &'a Schedule {
+    &plans[due.id.plan].schedules[due.id.schedule]
+}
+
+/// whether something more urgent than `priority` is waiting to run and the
+/// device meets its requirements, so it wouldn't just be deferred again
+fn preempts(
+    plans: &[Plan],
+    pending: &[PendingRun],
+    scheduler: &Scheduler,
+    device: &Mutex<ADBServerDevice>,
+    priority: i32,
+) -> bool {
+    let now = Local::now();
+    pending
+        .iter()
+        .filter(|run| run.is_ready(now))
+        .map(|run| &run.due)
+        .chain(scheduler.peek_due(now))
+        .map(|due| schedule_of(plans, due))
+        .filter(|schedule| schedule.priority > priority)
+        .any(|schedule| {
+            let mut device = device.lock().unwrap();
+            unmet_requirement(&mut device, &schedule.device).is_ok_and(|unmet| unmet.is_none())
+        })
+}
+
+/// the first of `requires` the device doesn't meet
+fn unmet_requirement(
+    device: &mut ADBServerDevice,
+    requires: &DeviceRequirements,
+) -> Result<Option<String>, Box<dyn Error>> {
+    if requires.min_battery.is_some() || requires.charging {
+        let battery = device.battery_status()?;
+        if let Some(min_battery) = requires.min_battery {
+            if battery.level < min_battery {
+                return Ok(Some(format!("battery at {}%", battery.level)));
+            }
+        }
+        if requires.charging && !battery.charging {
+            return Ok(Some("device not charging".to_owned()));
+        }
+    }
+    if requires.wifi && !device.is_wifi_connected()? {
+        return Ok(Some("Wi-Fi not connected".to_owned()));
+    }
+    if requires.unlocked && !device.is_screen_unlocked()? {
+        return Ok(Some("screen locked".to_owned()));
+    }
+    Ok(None)
+}
+
+fn run_schedule(
+    plan_engine: &mut PlanEngine,
+    history: &mut RunHistory,
+    plan: &Plan,
+    schedule: &Schedule,
+    next_step: usize,
+    preempted: &dyn Fn() -> bool,
+) -> Result<ScheduleProgress, Box<dyn Error>> {
+    let routines = match &schedule.action {
+        ScheduleActions::Routines(vec) => vec,
+        ScheduleActions::Script(path) => {
+            let location = schedule.start_at.as_deref();
+            return run_routine(plan_engine, history, plan, path, location, 0, preempted);
+        }
+    };
+    let mut outcome = RunOutcome::Success;
+    let routines = plan.ordered_routines(routines);
+    for (i, routine) in routines.into_iter().enumerate().skip(next_step) {
+        if preempted() {
+            return Ok(ScheduleProgress::Interrupted(i));
+        }
+        let location = plan.routine_location.get(routine).map(String::as_str);
+        match run_routine(plan_engine, history, plan, routine, location, i, preempted)? {
+            ScheduleProgress::Done(RunOutcome::Failed) => outcome = RunOutcome::Failed,
+            ScheduleProgress::Done(RunOutcome::Skipped) if outcome == RunOutcome::Success => {
+                outcome = RunOutcome::Skipped
+            }
+            ScheduleProgress::Done(_) => {}
+            interrupted => return Ok(interrupted),
+        }
+    }
+    Ok(ScheduleProgress::Done(outcome))
+}
+
+/// the first prerequisite of `routine` that didn't happen this game day
+fn unmet_dependency(history: &RunHistory, plan: &Plan, routine: &Path) -> Option<String> {
+    let settings = plan.routine_settings(routine);
+    let today = plan.game_day(&Local::now());
+    let ran_today = |dep: &Path| {
+        history
+            .routine(plan, dep)
+            .filter(|record| record.game_day == today)
+            .map(|record| record.outcome)
+    };
+    for dep in &settings.requires {
+        match ran_today(dep) {
+            Some(RunOutcome::Success) => {}
+            Some(_) => return Some(format!("required routine {:?} didn't succeed", dep)),
+            None => return Some(format!("required routine {:?} hasn't run", dep)),
+        }
+    }
+    for dep in &settings.after {
+        if ran_today(dep).is_none() {
+            return Some(format!("routine {:?} hasn't run yet", dep));
+        }
+    }
+    None
+}
+
+/// navigates to `location` and runs `routine`, retrying as the plan's routine
+/// settings say, and records how it went. Only fails if the history can't be
+/// saved
+fn run_routine(
+    plan_engine: &mut PlanEngine,
+    history: &mut RunHistory,
+    plan: &Plan,
+    routine: &Path,
+    location: Option<&str>,
+    step: usize,
+    preempted: &dyn Fn() -> bool,
+) -> Result<ScheduleProgress, Box<dyn Error>> {
+    let settings = plan.routine_settings(routine);
+    if let Some(reason) = unmet_dependency(history, plan, routine) {
+        println!("Skipping routine {:?}, {}", routine, reason);
+        history.record_routine(plan, routine, RunOutcome::Skipped, 0, Some(reason))?;
+        return Ok(ScheduleProgress::Done(RunOutcome::Skipped));
+    }
+    let attempts = settings.attempts.max(1);
+    let mut backoff = settings.backoff;
+    let mut last_err = None;
+    for attempt in 1..=attempts {
+        if attempt > 1 {
+            if let Some(delay) = backoff {
+                sleep(delay);
+                backoff = Some(delay.saturating_mul(2));
+            }
+            if preempted() {
+                return Ok(ScheduleProgress::Interrupted(step));
+            }
+            println!("Retrying routine {:?} ({}/{})", routine, attempt, attempts);
+            if settings.restart_app {
+                if let Err(err) = plan_engine.restart_app() {
+                    eprintln!("failed to restart the app: {}", err);
+                    last_err = Some(err);
+                    continue;
+                }
+            }
+        }
+
+        let res = location
+            .map_or(Ok(()), |location| {
+                plan_engine.navigate_to_interruptible(location, preempted)
+            })
+            .and_then(|()| {
+                println!("Running script {:?}", routine);
+                plan_engine.run_script(routine)
+            });
+        match res {
+            Ok(()) => {
+                history.record_routine(plan, routine, RunOutcome::Success, attempt, None)?;
+                return Ok(ScheduleProgress::Done(RunOutcome::Success));
+            }
+            Err(err) if err.is::<InterruptedError>() => {
+                return Ok(ScheduleProgress::Interrupted(step));
+            }
+            Err(err) => {
+                eprintln!(
+                    "routine {:?} failed (attempt {}/{}): {}",
+                    routine, attempt, attempts, err
+                );
+                last_err = Some(err);
+            }
+        }
+    }
+    let error = last_err.map(|err| err.to_string());
+    history.record_routine(plan, routine, RunOutcome::Failed, attempts, error)?;
+    Ok(ScheduleProgress::Done(RunOutcome::Failed))
+}
@@ -0,0 +1,308 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Local, TimeDelta};
+
+use crate::{
+    def::{CatchUp, Plan, Schedule},
+    history::{RunHistory, RunOutcome},
+};
+
+/// how late a firing may run under `CatchUp::Skip` without a `max_lateness`
+const DEFAULT_SKIP_LATENESS: Duration = Duration::from_secs(5 * 60);
+/// most firings `CatchUp::All` will replay, the earliest ones in time
+const MAX_CATCH_UP_RUNS: usize = 100;
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ScheduleId {
+    pub plan: usize,
+    pub schedule: usize,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DueSchedule {
+    pub id: ScheduleId,
+    /// the calendar time the schedule was due at
+    pub firing: DateTime<Local>,
+}
+
+/// keeps the next firing time of every schedule of every loaded plan
+#[derive(Clone, Debug)]
+pub struct Scheduler {
+    upcoming: Vec<DueSchedule>,
+}
+
+impl Scheduler {
+    /// schedules that already ran continue from their last recorded firing, so
+    /// a run that never finished or firings missed while we were down go
+    /// through the catch-up policy on the first `take_due`
+    pub fn new(plans: &[Plan], history: &RunHistory, now: DateTime<Local>) -> Self {
+        let mut upcoming = Vec::new();
+        for (plan_idx, plan) in plans.iter().enumerate() {
+            for (schedule_idx, schedule) in plan.schedules.iter().enumerate() {
+                let id = ScheduleId {
+                    plan: plan_idx,
+                    schedule: schedule_idx,
+                };
+                let record = history.schedule(plan, &schedule.history_key(schedule_idx));
+                let next = match record {
+                    Some(record) if record.outcome == RunOutcome::Running => {
+                        println!(
+                            "schedule `{}` in plan {} did not finish last time",
+                            schedule.on_calendar,
+                            plan.workdir.display()
+                        );
+                        Some(record.firing)
+                    }
+                    Some(record) => plan.next_firing(&schedule.on_calendar, &record.firing),
+                    None => plan.next_firing(&schedule.on_calendar, &now),
+                };
+                match next {
+                    Some(firing) => upcoming.push(DueSchedule { id, firing }),
+                    None => eprintln!(
+                        "schedule `{}` in plan {} never fires",
+                        schedule.on_calendar,
+                        plan.workdir.display()
+                    ),
+                }
+            }
+        }
+        Self { upcoming }
+    }
+
+    pub fn peek_due(&self, now: DateTime<Local>) -> impl Iterator<Item = &DueSchedule> {
+        self.upcoming.iter().filter(move |due| due.firing <= now)
+    }
+
+    pub fn next_firing(&self) -> Option<DateTime<Local>> {
+        self.upcoming.iter().map(|due| due.firing).min()
+    }
+
+    /// removes every schedule due at `now`, applying each schedule's catch-up
+    /// policy to the firings it missed, and queues their next firing
+    pub fn take_due(&mut self, plans: &[Plan], now: DateTime<Local>) -> Vec<DueSchedule> {
+        let (first_due, upcoming): (Vec<_>, Vec<_>) =
+            self.upcoming.drain(..).partition(|due| due.firing <= now);
+        self.upcoming = upcoming;
+
+        let mut due = Vec::new();
+        for DueSchedule { id, firing } in first_due {
+            let plan = &plans[id.plan];
+            let schedule = &plan.schedules[id.schedule];
+            let (runs, skipped) = catch_up(plan, schedule, firing, now);
+            if skipped {
+                println!(
+                    "skipping missed firing(s) of schedule `{}` in plan {}",
+                    schedule.on_calendar,
+                    plan.workdir.display()
+                );
+            }
+            due.extend(runs.into_iter().map(|firing| DueSchedule { id, firing }));
+            if let Some(firing) = plan.next_firing(&schedule.on_calendar, &now) {
+                self.upcoming.push(DueSchedule { id, firing });
+            }
+        }
+        due.sort_by_key(|due| (due.firing, due.id));
+        due
+    }
+}
+
+/// how late a firing of `schedule` may still start
+pub fn max_lateness(schedule: &Schedule) -> Option<Duration> {
+    match schedule.catch_up {
+        CatchUp::Skip => Some(schedule.max_lateness.unwrap_or(DEFAULT_SKIP_LATENESS)),
+        CatchUp::Once | CatchUp::All => schedule.max_lateness,
+    }
+}
+
+/// the firings from `first` up to `now` that `schedule`'s catch-up policy
+/// still runs, and whether it dropped any. Only the firings that may run are
+/// walked, so a frequent schedule stays cheap after a long outage
+fn catch_up(
+    plan: &Plan,
+    schedule: &Schedule,
+    first: DateTime<Local>,
+    now: DateTime<Local>,
+) -> (Vec<DateTime<Local>>, bool) {
+    let earliest = max_lateness(schedule)
+        .and_then(|max| TimeDelta::from_std(max).ok())
+        .and_then(|max| now.checked_sub_signed(max))
+        .map_or(first, |earliest| earliest.max(first));
+    match schedule.catch_up {
+        CatchUp::Skip | CatchUp::Once => {
+            let last = last_firing(plan, schedule, first, now);
+            if last >= earliest {
+                (vec![last], last != first)
+            } else {
+                (Vec::new(), true)
+            }
+        }
+        CatchUp::All => {
+            let mut next = if earliest == first {
+                Some(first)
+            } else {
+                plan.next_firing(
+                    &schedule.on_calendar,
+                    &(earliest - TimeDelta::nanoseconds(1)),
+                )
+            };
+            let mut runs = Vec::new();
+            while let Some(firing) = next.filter(|next| *next <= now) {
+                if runs.len() == MAX_CATCH_UP_RUNS {
+                    return (runs, true);
+                }
+                runs.push(firing);
+                next = plan.next_firing(&schedule.on_calendar, &firing);
+            }
+            let skipped = runs.first() != Some(&first);
+            (runs, skipped)
+        }
+    }
+}
+
+/// the latest firing of `schedule` between `first` and `now`. Looks back from
+/// `now` in growing windows instead of walking every firing since `first`
+fn last_firing(
+    plan: &Plan,
+    schedule: &Schedule,
+    first: DateTime<Local>,
+    now: DateTime<Local>,
+) -> DateTime<Local> {
+    let mut window = TimeDelta::minutes(1);
+    loop {
+        let from = now.checked_sub_signed(window).unwrap_or(first);
+        let found = if from <= first {
+            Some(first)
+        } else {
+            plan.next_firing(&schedule.on_calendar, &from)
+                .filter(|found| *found <= now)
+        };
+        if let Some(mut last) = found {
+            while let Some(next) = plan
+                .next_firing(&schedule.on_calendar, &last)
+                .filter(|next| *next <= now)
+            {
+                last = next;
+            }
+            return last;
+        }
+        window = window * 2;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn at(s: &str) -> DateTime<Local> {
+        DateTime::parse_from_rfc3339(s)
+            .unwrap()
+            .with_timezone(&Local)
+    }
+
+    /// a plan in UTC with the single schedule `schedule`
+    fn plan(name: &str, schedule: &str) -> Plan {
+        let dir = std::env::temp_dir().join(format!("plan-a-scheduler-{}", name));
+        fs::create_dir_all(&dir).unwrap();
+        let toml = format!(
+            "package = \"test\"\nactivity = \"test\"\ntimezone = \"+00:00\"\n\n\
+             [[schedules]]\nscript = \"test.lua\"\n{}\n",
+            schedule
+        );
+        fs::write(dir.join("plan.toml"), toml).unwrap();
+        Plan::new(&dir).unwrap().0
+    }
+
+    fn runs(plan: &Plan, first: &str, now: &str) -> (Vec<DateTime<Local>>, bool) {
+        catch_up(plan, &plan.schedules[0], at(first), at(now))
+    }
+
+    #[test]
+    fn skip_only_runs_on_time_firings() {
+        let plan = plan("skip", "on_calendar = \"*:00\"\ncatch_up = \"skip\"");
+        assert_eq!(
+            runs(&plan, "2024-05-01T10:00:00Z", "2024-05-01T10:03:00Z"),
+            (vec![at("2024-05-01T10:00:00Z")], false)
+        );
+        assert_eq!(
+            runs(&plan, "2024-05-01T10:00:00Z", "2024-05-01T13:04:00Z"),
+            (vec![at("2024-05-01T13:00:00Z")], true)
+        );
+        assert_eq!(
+            runs(&plan, "2024-05-01T10:00:00Z", "2024-05-01T13:30:00Z"),
+            (vec![], true)
+        );
+    }
+
+    #[test]
+    fn once_runs_the_latest_firing() {
+        let plan = plan("once", "on_calendar = \"*:00\"\ncatch_up = \"once\"");
+        assert_eq!(
+            runs(&plan, "2024-05-01T10:00:00Z", "2024-05-01T10:30:00Z"),
+            (vec![at("2024-05-01T10:00:00Z")], false)
+        );
+        assert_eq!(
+            runs(&plan, "2024-05-01T10:00:00Z", "2024-05-08T13:30:00Z"),
+            (vec![at("2024-05-08T13:00:00Z")], true)
+        );
+    }
+
+    #[test]
+    fn once_drops_firings_later_than_max_lateness() {
+        let plan = plan(
+            "once-lateness",
+            "on_calendar = \"*:00\"\ncatch_up = \"once\"\nmax_lateness = \"20m\"",
+        );
+        assert_eq!(
+            runs(&plan, "2024-05-01T10:00:00Z", "2024-05-01T13:15:00Z"),
+            (vec![at("2024-05-01T13:00:00Z")], true)
+        );
+        assert_eq!(
+            runs(&plan, "2024-05-01T10:00:00Z", "2024-05-01T13:30:00Z"),
+            (vec![], true)
+        );
+    }
+
+    #[test]
+    fn all_runs_every_missed_firing() {
+        let plan = plan("all", "on_calendar = \"*:00\"\ncatch_up = \"all\"");
+        assert_eq!(
+            runs(&plan, "2024-05-01T10:00:00Z", "2024-05-01T13:30:00Z"),
+            (
+                vec![
+                    at("2024-05-01T10:00:00Z"),
+                    at("2024-05-01T11:00:00Z"),
+                    at("2024-05-01T12:00:00Z"),
+                    at("2024-05-01T13:00:00Z"),
+                ],
+                false
+            )
+        );
+    }
+
+    #[test]
+    fn all_drops_firings_later_than_max_lateness() {
+        let plan = plan(
+            "all-lateness",
+            "on_calendar = \"*:00\"\ncatch_up = \"all\"\nmax_lateness = \"2h\"",
+        );
+        assert_eq!(
+            runs(&plan, "2024-05-01T10:00:00Z", "2024-05-01T13:30:00Z"),
+            (
+                vec![at("2024-05-01T12:00:00Z"), at("2024-05-01T13:00:00Z")],
+                true
+            )
+        );
+    }
+
+    #[test]
+    fn all_runs_at_most_max_catch_up_runs() {
+        let plan = plan("all-cap", "on_calendar = \"*:*\"\ncatch_up = \"all\"");
+        let (runs, skipped) = runs(&plan, "2024-05-01T00:00:00Z", "2024-06-01T00:00:00Z");
+        assert_eq!(runs.len(), MAX_CATCH_UP_RUNS);
+        assert_eq!(runs[0], at("2024-05-01T00:00:00Z"));
+        assert_eq!(runs[MAX_CATCH_UP_RUNS - 1], at("2024-05-01T01:39:00Z"));
+        assert!(skipped);
+    }
+}
@@ -0,0 +1,125 @@
+use std::{
+    error::Error,
+    sync::{Arc, Mutex},
+};
+
+use adb_client::ADBServerDevice;
+use image_new::imageops::{self, FilterType};
+
+use crate::{adb_device_ext::ADBDeviceSimpleCommand, image_stuff::RgbaImageNew};
+
+/// maps a plan's design resolution onto the device's framebuffer. The game is
+/// assumed to be scaled to fit and centered, with bars on the remaining sides
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Viewport {
+    design: (u32, u32),
+    scale: f32,
+    /// letterbox or pillarbox bars before the game area
+    offset: (f32, f32),
+}
+
+impl Viewport {
+    pub fn new(design: (u32, u32), device: (u32, u32)) -> Self {
+        let scale = (device.0 as f32 / design.0 as f32).min(device.1 as f32 / design.1 as f32);
+        let offset = (
+            (device.0 as f32 - design.0 as f32 * scale) / 2.0,
+            (device.1 as f32 - design.1 as f32 * scale) / 2.0,
+        );
+        Self {
+            design,
+            scale,
+            offset,
+        }
+    }
+
+    pub fn device_pos(&self, pos: (u32, u32)) -> (u32, u32) {
+        (
+            (self.offset.0 + pos.0 as f32 * self.scale).round() as u32,
+            (self.offset.1 + pos.1 as f32 * self.scale).round() as u32,
+        )
+    }
+
+    /// the game area of `screenshot`, resized to the design resolution
+    pub fn design_image(&self, screenshot: &RgbaImageNew) -> RgbaImageNew {
+        let x = (self.offset.0.round() as u32).min(screenshot.width());
+        let y = (self.offset.1.round() as u32).min(screenshot.height());
+        let width =
+            ((self.design.0 as f32 * self.scale).round() as u32).min(screenshot.width() - x);
+        let height =
+            ((self.design.1 as f32 * self.scale).round() as u32).min(screenshot.height() - y);
+        let area = imageops::crop_imm(screenshot, x, y, width, height);
+        if (width, height) == self.design {
+            return area.to_image();
+        }
+        imageops::resize(&*area, self.design.0, self.design.1, FilterType::Triangle)
+    }
+}
+
+/// the device as a plan sees it, in the plan's design resolution if it has one
+#[derive(Clone)]
+pub struct ScaledDevice {
+    device: Arc<Mutex<ADBServerDevice>>,
+    design: Option<(u32, u32)>,
+    /// from the latest screenshot, the framebuffer changes size on rotation
+    viewport: Arc<Mutex<Option<Viewport>>>,
+}
+
+impl ScaledDevice {
+    pub fn new(device: Arc<Mutex<ADBServerDevice>>, design: Option<(u32, u32)>) -> Self {
+        Self {
+            device,
+            design,
+            viewport: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn screenshot(&self) -> Result<RgbaImageNew, Box<dyn Error>> {
+        let screenshot = self.device.lock().unwrap().framebuffer_inner()?;
+        let Some(design) = self.design else {
+            return Ok(screenshot);
+        };
+        let viewport = Viewport::new(design, screenshot.dimensions());
+        *self.viewport.lock().unwrap() = Some(viewport);
+        Ok(viewport.design_image(&screenshot))
+    }
+
+    fn device_pos(&self, pos: (u32, u32)) -> Result<(u32, u32), Box<dyn Error>> {
+        if self.design.is_none() {
+            return Ok(pos);
+        }
+        if self.viewport.lock().unwrap().is_none() {
+            self.screenshot()?;
+        }
+        let viewport = self
+            .viewport
+            .lock()
+            .unwrap()
+            .expect("a screenshot to set it");
+        Ok(viewport.device_pos(pos))
+    }
+
+    pub fn tap(&self, x: u32, y: u32) -> Result<(), Box<dyn Error>> {
+        let (x, y) = self.device_pos((x, y))?;
+        self.device.lock().unwrap().tap(x, y)
+    }
+
+    pub fn swipe(
+        &self,
+        x1: u32,
+        y1: u32,
+        x2: u32,
+        y2: u32,
+        duration_ms: Option<u32>,
+    ) -> Result<(), Box<dyn Error>> {
+        let (x1, y1) = self.device_pos((x1, y1))?;
+        let (x2, y2) = self.device_pos((x2, y2))?;
+        self.device
+            .lock()
+            .unwrap()
+            .swipe(x1, y1, x2, y2, duration_ms)
+    }
+
+    pub fn back(&self) -> Result<(), Box<dyn Error>> {
+        self.device.lock().unwrap().back()
+    }
+}